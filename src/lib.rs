@@ -0,0 +1,3020 @@
+mod json;
+#[cfg(test)]
+mod tests;
+
+use std::{borrow::Cow, cell::Cell, collections::HashMap, fmt::{self, Display}, io::{self, BufRead, Write}, ops::{Index, IndexMut, Range}, num::Wrapping, sync::atomic::{AtomicBool, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+pub type Register = usize;
+pub type Label = String;
+
+/// A problem with a program found while parsing or running it. Every `line` is 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InterpretError {
+    /// An instruction jumps to a label that is not defined.
+    UnknownLabel {
+        line: usize,
+        name: String,
+    },
+    /// An instruction names a register beyond the last one.
+    UnknownRegister {
+        line: usize,
+        reg: Register,
+    },
+    /// A register given a starting value by `run_source` does not exist. It has no line, as it is not in the
+    /// program.
+    UnknownInitialRegister {
+        reg: Register,
+    },
+    /// The mnemonic at the start of a line is not an instruction or directive.
+    GarbageInstruction {
+        line: usize,
+        text: String,
+    },
+    /// A jump target is before the start or beyond the end of the program.
+    JumpOutOfRange {
+        line: usize,
+        target: i64,
+    },
+    /// Any other problem with a line. `token` is the part of the line the error is about, if it is about
+    /// a single token.
+    Parse {
+        line: usize,
+        message: String,
+        token: Option<String>,
+    },
+    /// A division or modulo by a register holding zero.
+    DivideByZero {
+        line: usize,
+    },
+    /// Reading the input or writing the output of the program failed. `message` describes the underlying
+    /// `io::Error`.
+    Io {
+        line: usize,
+        message: String,
+    },
+    /// Any other problem while running the program, such as a memory access out of range.
+    Runtime {
+        line: usize,
+        message: String,
+    },
+    /// `quit` was entered at the debug prompt, which ends the run without anything having gone wrong. The caller
+    /// decides what happens next.
+    Quit {
+        line: usize,
+    },
+    /// Several problems found together, in the order of their lines, such as every jump to an unknown label.
+    Multiple(Vec<InterpretError>),
+}
+
+impl InterpretError {
+    fn parse(index: usize, message: String) -> InterpretError {
+        InterpretError::Parse { line: index + 1, message, token: None }
+    }
+
+    fn parse_at(index: usize, message: String, token: &str) -> InterpretError {
+        InterpretError::Parse { line: index + 1, message, token: Some(token.to_string()) }
+    }
+
+    /// Fails with the errors in `errors`, or `Multiple` if there is more than one.
+    fn collect(mut errors: Vec<InterpretError>) -> Result<(), InterpretError> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(InterpretError::Multiple(errors)),
+        }
+    }
+
+    fn runtime(index: usize, message: String) -> InterpretError {
+        InterpretError::Runtime { line: index + 1, message }
+    }
+
+    /// The line the error is on, or `None` if it is not about a line of the program.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            InterpretError::UnknownLabel { line, .. } | InterpretError::UnknownRegister { line, .. }
+            | InterpretError::GarbageInstruction { line, .. } | InterpretError::JumpOutOfRange { line, .. }
+            | InterpretError::Parse { line, .. } | InterpretError::DivideByZero { line } | InterpretError::Io { line, .. }
+            | InterpretError::Runtime { line, .. } | InterpretError::Quit { line } => Some(*line),
+            InterpretError::UnknownInitialRegister { .. } => None,
+            InterpretError::Multiple(errors) => errors.first().and_then(InterpretError::line),
+        }
+    }
+
+    /// The individual problems, which is just this error unless it is `Multiple`.
+    pub fn errors(&self) -> &[InterpretError] {
+        match self {
+            InterpretError::Multiple(errors) => errors,
+            error => std::slice::from_ref(error),
+        }
+    }
+
+    /// The name of the variant, used as the `kind` of JSON errors.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InterpretError::UnknownLabel { .. } => "UnknownLabel",
+            InterpretError::UnknownRegister { .. } => "UnknownRegister",
+            InterpretError::UnknownInitialRegister { .. } => "UnknownInitialRegister",
+            InterpretError::GarbageInstruction { .. } => "GarbageInstruction",
+            InterpretError::JumpOutOfRange { .. } => "JumpOutOfRange",
+            InterpretError::Parse { .. } => "Parse",
+            InterpretError::DivideByZero { .. } => "DivideByZero",
+            InterpretError::Io { .. } => "Io",
+            InterpretError::Runtime { .. } => "Runtime",
+            InterpretError::Quit { .. } => "Quit",
+            InterpretError::Multiple(_) => "Multiple",
+        }
+    }
+
+    /// The error as a single line JSON object with its kind, line and message, or one line for each of the errors
+    /// of `Multiple`.
+    pub fn to_json(&self) -> String {
+        let objects: Vec<_> = self.errors()
+            .iter()
+            .map(|error| {
+                let line = error.line().map_or("null".to_string(), |line| line.to_string());
+                format!("{{\"kind\":\"{}\",\"line\":{},\"message\":\"{}\"}}", error.kind(), line, json::escape(&error.to_string()))
+            })
+            .collect();
+        objects.join("\n")
+    }
+
+    /// The part of the line the error is about, if it is about a single token.
+    fn token(&self) -> Option<String> {
+        match self {
+            InterpretError::UnknownLabel { name, .. } => Some(name.clone()),
+            InterpretError::UnknownRegister { reg, .. } => Some(format!("r{}", reg)),
+            InterpretError::GarbageInstruction { text, .. } => Some(text.clone()),
+            InterpretError::JumpOutOfRange { target, .. } => Some(target.to_string()),
+            InterpretError::Parse { token, .. } => token.clone(),
+            InterpretError::UnknownInitialRegister { .. } | InterpretError::DivideByZero { .. } | InterpretError::Io { .. }
+            | InterpretError::Runtime { .. } | InterpretError::Quit { .. } | InterpretError::Multiple(_) => None,
+        }
+    }
+
+    /// Shows the offending line of `source` with a caret under the token the error is about, or `None` if the
+    /// error is not about a single token.
+    pub fn annotate(&self, source: &str) -> Option<String> {
+        let (line, token) = (self.line()?, self.token()?);
+        let text = source.lines().nth(line.checked_sub(1)?)?;
+        let column = text.to_ascii_lowercase().find(&token.to_ascii_lowercase())?;
+        let number = line.to_string();
+        let carets = "^".repeat(token.chars().count().max(1));
+        Some(format!("{} | {}\n{} | {}{}", number, text, " ".repeat(number.len()), " ".repeat(text[..column].chars().count()), carets))
+    }
+}
+
+impl Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpretError::UnknownLabel { line, name } => write!(f, "unknown label `{}` on line {}", name, line),
+            InterpretError::UnknownRegister { reg, .. } | InterpretError::UnknownInitialRegister { reg } => {
+                write!(f, "r{} does not exist", reg)
+            },
+            InterpretError::GarbageInstruction { text, .. } => write!(f, "garbage instruction `{}`", text),
+            InterpretError::JumpOutOfRange { line, target } => write!(f, "jump target {} out of range on line {}", target, line),
+            InterpretError::DivideByZero { line } => write!(f, "division by zero on line {}", line),
+            InterpretError::Io { line, message } => write!(f, "IO error on line {}: {}", line, message),
+            InterpretError::Quit { line } => write!(f, "quit at the debug prompt on line {}", line),
+            InterpretError::Parse { message, .. } | InterpretError::Runtime { message, .. } => write!(f, "{}", message),
+            InterpretError::Multiple(errors) => {
+                let messages: Vec<_> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "{}", messages.join("\n"))
+            },
+        }
+    }
+}
+
+impl std::error::Error for InterpretError {}
+
+/// Number of memory words available to `load` and `store` unless resized with `State::resize_memory`.
+pub const MEMORY_WORDS: usize = 1024;
+
+/// Number of registers unless changed with `State::resize_registers`.
+pub const REGISTERS: usize = 8;
+
+/// Number of memory words reserved for the stack of a program sharing memory with the programs loaded after it, unless
+/// it declares the size with `.stack`.
+pub const SHARED_STACK_WORDS: usize = 64;
+
+/// Time in milliseconds read by `time` in sandbox mode, so the output doesn't depend on when the program runs.
+pub const SANDBOX_TIME: u64 = 0;
+
+/// Layout of the register table printed by `State::dump`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Unsigned, signed and hex columns.
+    #[default]
+    TableWide,
+    /// Only the hex column.
+    TableNarrow,
+}
+
+/// Longest line in bytes accepted by the parser unless configured otherwise.
+pub const DEFAULT_MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Settings changing how programs are parsed, how instructions behave and how the state is shown, fixed before the
+/// program is loaded.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub dump_format: DumpFormat,
+    /// Base of the first column of `DumpFormat::TableWide`, one of 2, 8, 10 and 16. With 16 the separate hex column
+    /// is left out.
+    pub dump_radix: u32,
+    /// Out of range loads read 0 and out of range stores are dropped instead of being errors.
+    pub soft_memory: bool,
+    /// Results that cannot be represented are errors instead of being saturated.
+    pub strict_overflow: bool,
+    /// `debug` only dumps the state instead of stopping at the debug prompt, reading input is an error, and `time`
+    /// reads `SANDBOX_TIME`.
+    pub sandbox: bool,
+    /// Lines longer than this many bytes are a parse error.
+    pub max_line_len: usize,
+    /// Dumps label the registers from R1 instead of R0. Programs still name them from r0.
+    pub one_based_regs: bool,
+    /// Only `debug` instructions tagged with one of these stop, all others do nothing.
+    pub debug_only: Option<Vec<String>>,
+    /// Printed before every command read at the debug prompt.
+    pub debug_prompt: Option<String>,
+    /// Comment markers only start a comment at the start of a line or after whitespace.
+    pub strict_comments: bool,
+    /// Reading or writing this memory address stops at the debug prompt after the instruction.
+    pub trap_addr: Option<usize>,
+    /// Number of bits in a register, one of 8, 16, 32 and 64. Results are truncated to this many bits.
+    pub width: u32,
+    /// Labels that differ only in case are the same label, as mnemonics always are.
+    pub case_insensitive_labels: bool,
+    /// Number of memory words, starting from address 0, shown after the registers by `State::dump`.
+    pub dump_memory: usize,
+}
+
+impl Config {
+    /// Whether a `debug` instruction with the given tag stops.
+    pub fn debug_enabled(&self, tag: Option<&str>) -> bool {
+        match &self.debug_only {
+            Some(tags) => tag.is_some_and(|tag| tags.iter().any(|t| t == tag)),
+            None => true,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            dump_format: DumpFormat::default(),
+            dump_radix: 10,
+            soft_memory: false,
+            strict_overflow: false,
+            sandbox: false,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            one_based_regs: false,
+            debug_only: None,
+            debug_prompt: None,
+            strict_comments: false,
+            trap_addr: None,
+            width: 64,
+            case_insensitive_labels: false,
+            dump_memory: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct State {
+    pub registers: Vec<Wrapping<u64>>,
+    pub zero: bool,
+    /// Set by `add`, `sub`, `inc` and `dec` when the unsigned result wrapped around.
+    pub carry: bool,
+    /// Bit 63 of the difference computed by `cmp`.
+    pub sign: bool,
+    /// Set by `cmp` when the signed difference overflowed.
+    pub overflow: bool,
+    pub labels: HashMap<String, usize>,
+    /// Labels defined on data directives, mapping to the memory address of their data.
+    pub data_labels: HashMap<String, usize>,
+    /// Names given to registers with `.alias`.
+    pub aliases: HashMap<String, Register>,
+    pub steps: u64,
+    /// Number of times each instruction of the program has been executed, by index.
+    pub executions: Vec<u64>,
+    pub memory: Vec<Wrapping<u64>>,
+    pub config: Config,
+    /// Program counter recorded by the `mark` debugger command.
+    pub mark: Option<usize>,
+    /// Set by the `s` debugger command to stop at the debug prompt again after the next instruction, and cleared by
+    /// continuing.
+    pub single_step: bool,
+    /// Set by `halt reg` to the low 8 bits of `reg`, which the interpreter exits with. Never set in sandbox mode.
+    pub exit_code: Option<u8>,
+    /// Snapshots taken with the `save` debugger command, by name.
+    pub snapshots: HashMap<String, StateSnapshot>,
+    /// Number of instructions in the loaded program, including empty lines.
+    pub program_len: usize,
+    /// The loaded program with its inline routines expanded, so its lines match the instructions and the lines of
+    /// errors. If expanding them failed it is the program as written, since that is what the error is about.
+    pub source: String,
+    /// Registers declared by `.input` to be set before the program reads them.
+    pub inputs: Vec<Register>,
+    /// Registers declared by `.output` to be written by the program.
+    pub outputs: Vec<Register>,
+    /// Return addresses saved by `call` and its conditional variants.
+    pub call_stack: Vec<usize>,
+    /// Address of the value on top of the stack, which grows down from `stack_base`.
+    pub sp: usize,
+    /// Initial stack pointer, the end of memory unless set with `set_initial_sp`.
+    stack_base: usize,
+    /// The index of the line each label, including data labels, is defined on.
+    label_lines: HashMap<String, usize>,
+    /// Whether the last instruction `reads` or `writes` the trapped address, and the value.
+    trapped: Cell<Option<(&'static str, Wrapping<u64>)>>,
+    /// Largest number of values that were on the stack at once.
+    pub peak_stack_depth: usize,
+    /// Number of memory words reserved for the stack by `.stack`.
+    stack_size: Option<usize>,
+    /// End of the memory reserved for the stack, the end of memory unless the stack is below that of another program.
+    stack_top: usize,
+    /// Memory address where the next data directive places its values.
+    data_pointer: usize,
+    /// Bumped by every write to memory once the program is loaded, so `fingerprint` can tell the memory changed
+    /// without hashing all of it.
+    memory_generation: u64,
+}
+
+/// The registers, flags, memory and stacks of a `State`, taken with `State::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateSnapshot {
+    registers: Vec<Wrapping<u64>>,
+    flags: [bool; 4],
+    memory: Vec<Wrapping<u64>>,
+    sp: usize,
+    call_stack: Vec<usize>,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State::new()
+    }
+}
+
+impl State {
+    pub fn new() -> State {
+        State {
+            registers: vec![Wrapping(0); REGISTERS],
+            zero: false,
+            carry: false,
+            sign: false,
+            overflow: false,
+            labels: HashMap::new(),
+            data_labels: HashMap::new(),
+            aliases: HashMap::new(),
+            steps: 0,
+            executions: Vec::new(),
+            memory: vec![Wrapping(0); MEMORY_WORDS],
+            config: Config::default(),
+            mark: None,
+            single_step: false,
+            exit_code: None,
+            snapshots: HashMap::new(),
+            program_len: 0,
+            source: String::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            call_stack: Vec::new(),
+            sp: MEMORY_WORDS,
+            stack_base: MEMORY_WORDS,
+            label_lines: HashMap::new(),
+            trapped: Cell::new(None),
+            peak_stack_depth: 0,
+            stack_size: None,
+            stack_top: MEMORY_WORDS,
+            data_pointer: 0,
+            memory_generation: 0,
+        }
+    }
+
+    /// Captures everything the program can change except the labels and the step count.
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            registers: self.registers.clone(),
+            flags: [self.zero, self.carry, self.sign, self.overflow],
+            memory: self.memory.clone(),
+            sp: self.sp,
+            call_stack: self.call_stack.clone(),
+        }
+    }
+
+    /// Puts the registers, flags, memory and stacks back to how they were when `snapshot` was taken.
+    pub fn restore(&mut self, snapshot: &StateSnapshot) {
+        self.registers = snapshot.registers.clone();
+        [self.zero, self.carry, self.sign, self.overflow] = snapshot.flags;
+        self.memory = snapshot.memory.clone();
+        self.memory_generation += 1;
+        self.sp = snapshot.sp;
+        self.call_stack = snapshot.call_stack.clone();
+    }
+
+    /// Replaces the memory by `words` words, all zero, with an empty stack at its end. Must be called before the
+    /// program is loaded since data directives write to the memory.
+    pub fn resize_memory(&mut self, words: usize) {
+        self.memory = vec![Wrapping(0); words];
+        self.sp = words;
+        self.stack_base = words;
+        self.stack_top = words;
+    }
+
+    /// Replaces the registers by `count` registers, all zero. Must be called before the program is loaded since the
+    /// parser rejects registers beyond the last one.
+    pub fn resize_registers(&mut self, count: usize) {
+        self.registers = vec![Wrapping(0); count];
+    }
+
+    /// The largest value a register holds with the configured width.
+    pub fn register_mask(&self) -> u64 {
+        u64::MAX >> (64 - self.config.width)
+    }
+
+    /// Reads `value`, truncated to the register width, as a signed number.
+    fn signed(&self, value: u64) -> i64 {
+        let unused = 64 - self.config.width;
+        ((value << unused) as i64) >> unused
+    }
+
+    /// A mask of the low `bits` bits of a register, or all of them if it is narrower.
+    fn low_bits(&self, bits: u32) -> u64 {
+        u64::MAX >> (64 - bits.min(self.config.width))
+    }
+
+    /// Sign-extends the low `bits` bits of `value` to the register width.
+    fn sign_extend(&self, value: u64, bits: u32) -> u64 {
+        let unused = 64 - bits.min(self.config.width);
+        (((value << unused) as i64) >> unused) as u64
+    }
+
+    /// The largest signed value a register holds with the configured width.
+    fn signed_max(&self) -> i64 {
+        (self.register_mask() >> 1) as i64
+    }
+
+    /// Rotates `value` left by `by` bits within the register width.
+    fn rotate_left(&self, value: u64, by: u64) -> u64 {
+        let by = (by % self.config.width as u64) as u32;
+        let value = value & self.register_mask();
+        (value << by | value.checked_shr(self.config.width - by).unwrap_or(0)) & self.register_mask()
+    }
+
+    /// Clears the bits of every register beyond the register width.
+    fn truncate_registers(&mut self) {
+        let mask = self.register_mask();
+        for reg in &mut self.registers {
+            reg.0 &= mask;
+        }
+    }
+
+    /// Takes over the memory of `other`, which has loaded an earlier program, so data directives of the program loaded
+    /// next are placed after the data of that one instead of overwriting it. The stack starts empty below the stack of
+    /// `other`, which is limited to `SHARED_STACK_WORDS` unless its program declared the size with `.stack`. Used for
+    /// programs sharing memory.
+    pub fn continue_memory(&mut self, other: &mut State) {
+        let free = other.stack_top - other.data_pointer;
+        other.stack_size.get_or_insert(SHARED_STACK_WORDS.min(free));
+        self.memory = other.memory.clone();
+        self.stack_top = other.stack_limit();
+        self.sp = self.stack_top;
+        self.stack_base = self.stack_top;
+        self.data_pointer = other.data_pointer;
+    }
+
+    /// Starts the stack at `sp` instead of the end of memory, so the first value pushed is stored at `sp - 1`.
+    /// Returns `None` if `sp` is beyond the end of memory.
+    pub fn set_initial_sp(&mut self, sp: usize) -> Option<()> {
+        (sp <= self.memory.len()).then(|| {
+            self.sp = sp;
+            self.stack_base = sp;
+        })
+    }
+
+    /// Writes `values` to memory at the data pointer and points `labels` at the first of them.
+    fn place_data(&mut self, labels: &[String], values: &[u64], index: usize) -> Result<(), InterpretError> {
+        let start = self.data_pointer;
+        let end = start + values.len();
+        if end > self.stack_top - self.stack_size.unwrap_or(0) {
+            return Err(InterpretError::parse(index, format!("data does not fit in memory on line {}", index + 1)));
+        }
+        for (cell, &value) in self.memory[start..end].iter_mut().zip(values) {
+            *cell = Wrapping(value);
+        }
+        self.data_pointer = end;
+        for label in labels {
+            let key = self.label_key(label).into_owned();
+            self.labels.remove(&key);
+            self.data_labels.insert(key, start);
+        }
+        Ok(())
+    }
+
+    /// Reserves the last `size` words of the memory left for the stack.
+    fn reserve_stack(&mut self, size: u64, index: usize) -> Result<(), InterpretError> {
+        if self.stack_size.is_some() {
+            return Err(InterpretError::parse(index, format!("stack size declared more than once on line {}", index + 1)));
+        }
+        let size = usize::try_from(size).ok()
+            .filter(|&size| size <= self.stack_top - self.data_pointer)
+            .ok_or_else(|| InterpretError::parse(index, format!("stack of {} words does not fit in memory on line {}", size, index + 1)))?;
+        self.stack_size = Some(size);
+        Ok(())
+    }
+
+    fn stack_is_empty(&self) -> bool {
+        self.sp == self.stack_base
+    }
+
+    /// Lowest address the stack may grow down to.
+    fn stack_limit(&self) -> usize {
+        self.stack_top - self.stack_size.unwrap_or(self.stack_top)
+    }
+
+    /// Remembers an access to `addr` if it is the trapped address.
+    fn trap(&self, addr: usize, access: &'static str, value: Wrapping<u64>) {
+        if self.config.trap_addr == Some(addr) {
+            self.trapped.set(Some((access, value)));
+        }
+    }
+
+    fn load(&self, addr: Wrapping<u64>, index: usize) -> Result<Wrapping<u64>, InterpretError> {
+        match usize::try_from(addr.0).ok().and_then(|addr| Some((addr, *self.memory.get(addr)?))) {
+            Some((addr, value)) => {
+                self.trap(addr, "reads", value);
+                Ok(value)
+            },
+            None if self.config.soft_memory => Ok(Wrapping(0)),
+            None => Err(InterpretError::runtime(index, format!("memory address {} out of range on line {}", addr, index + 1))),
+        }
+    }
+
+    fn store(&mut self, addr: Wrapping<u64>, value: Wrapping<u64>, index: usize) -> Result<(), InterpretError> {
+        match usize::try_from(addr.0).ok().filter(|&addr| addr < self.memory.len()) {
+            Some(addr) => {
+                self.memory[addr] = value;
+                self.memory_generation += 1;
+                self.trap(addr, "writes", value);
+            },
+            None if self.config.soft_memory => (),
+            None => return Err(InterpretError::runtime(index, format!("memory address {} out of range on line {}", addr, index + 1))),
+        }
+        Ok(())
+    }
+
+    /// Sets the `len` words of memory starting at `addr` to `value`.
+    fn fill(&mut self, addr: Wrapping<u64>, value: Wrapping<u64>, len: u64, index: usize) -> Result<(), InterpretError> {
+        let start = usize::try_from(addr.0).map_or(self.memory.len(), |addr| addr.min(self.memory.len()));
+        let end = match addr.0.checked_add(len).and_then(|end| usize::try_from(end).ok()).filter(|&end| end <= self.memory.len()) {
+            Some(end) => end,
+            None if self.config.soft_memory => self.memory.len(),
+            None => return Err(InterpretError::runtime(index, format!("memory block of {} words at address {} out of range on line {}", len, addr, index + 1))),
+        };
+        self.memory[start..end].fill(value);
+        self.memory_generation += 1;
+        if let Some(addr) = self.config.trap_addr.filter(|addr| (start..end).contains(addr)) {
+            self.trap(addr, "writes", value);
+        }
+        Ok(())
+    }
+
+    /// The name `label` is looked up by, which is lowercase with `Config::case_insensitive_labels`. Diagnostics
+    /// show the label as written.
+    fn label_key<'a>(&self, label: &'a str) -> Cow<'a, str> {
+        match self.config.case_insensitive_labels {
+            true => Cow::Owned(label.to_lowercase()),
+            false => Cow::Borrowed(label),
+        }
+    }
+
+    fn add_label(&mut self, label: String, index: usize) -> Result<(), InterpretError> {
+        let key = self.label_key(&label).into_owned();
+        if let Some(&defined) = self.label_lines.get(&key) {
+            return Err(InterpretError::parse_at(index, format!("label `{}` already defined on line {}, redefined on line {}", label, defined + 1, index + 1), &label));
+        }
+        self.label_lines.insert(key.clone(), index);
+        self.labels.insert(key, index);
+        Ok(())
+    }
+
+    /// Makes `name` stand for register `reg` in the instructions after the `.alias` at `index`.
+    fn add_alias(&mut self, name: &str, reg: Register, index: usize) -> Result<(), InterpretError> {
+        if read_reg_(&mut std::iter::once(name)).is_some() {
+            return Err(InterpretError::parse_at(index, format!("alias `{}` shadows a register on line {}", name, index + 1), name));
+        }
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(InterpretError::parse_at(index, format!("`{}` is not a valid alias on line {}", name, index + 1), name));
+        }
+        if self.aliases.contains_key(name) {
+            return Err(InterpretError::parse_at(index, format!("alias `{}` already defined, redefined on line {}", name, index + 1), name));
+        }
+        self.aliases.insert(name.to_string(), reg);
+        Ok(())
+    }
+
+    /// Saves the address after `index` on the call stack and returns the target of `label`.
+    fn call(&mut self, label: &str, index: usize) -> Result<usize, InterpretError> {
+        self.call_stack.push(index + 1);
+        self.resolve_label(label, index)
+    }
+
+    /// The index the jump at `index` to `label` continues at.
+    pub fn resolve_label(&self, label: &str, index: usize) -> Result<usize, InterpretError> {
+        let target = self.target(label).and_then(|target| usize::try_from(target).ok());
+        target.ok_or_else(|| InterpretError::UnknownLabel { line: index + 1, name: label.to_string() })
+    }
+
+    /// The value `lea` loads for `label`: the memory address of a data label or the index of any other jump target.
+    pub fn label_value(&self, label: &str, index: usize) -> Result<u64, InterpretError> {
+        match self.data_address(label).and_then(|addr| u64::try_from(addr).ok()) {
+            Some(addr) => Ok(addr),
+            None => self.resolve_label(label, index).map(|target| target as u64),
+        }
+    }
+
+    /// The memory address `label` refers to if it is a data label, optionally followed by an offset like `msg+2`.
+    fn data_address(&self, label: &str) -> Option<i64> {
+        let (name, offset) = split_offset(label);
+        let base = *self.data_labels.get(&*self.label_key(name))?;
+        i64::try_from(base).ok()?.checked_add(offset)
+    }
+
+    /// The index a jump target refers to: a label or an index, optionally followed by an offset like `loop+2`.
+    /// Returns `None` if the label is not defined or the target doesn't fit in an `i64`.
+    fn target(&self, label: &str) -> Option<i64> {
+        let (name, offset) = split_offset(label);
+        let base = match self.labels.get(&*self.label_key(name)) {
+            Some(&target) => target,
+            None => name.parse().ok()?,
+        };
+        i64::try_from(base).ok()?.checked_add(offset)
+    }
+
+    /// Parses `source` into instructions after expanding its inline routines, adding its labels to the state. Every
+    /// line is parsed even if an earlier one is malformed, so all of their problems are returned together.
+    pub fn load_program(&mut self, source: &str) -> Result<Vec<Instruction>, InterpretError> {
+        let expanded = match expand_inline(source, &self.config) {
+            Ok(expanded) => expanded,
+            Err(error) => {
+                self.source = source.to_string();
+                return Err(error);
+            },
+        };
+        let parsed: Vec<_> = expanded
+            .lines()
+            .enumerate()
+            .map(Instruction::parse(self))
+            .collect();
+        self.source = expanded;
+        let mut errors = Vec::new();
+        // Malformed lines are kept as `Noop` so the jump targets of the other lines can still be checked.
+        let instructions: Vec<_> = parsed.into_iter()
+            .map(|instruction| instruction.unwrap_or_else(|error| {
+                errors.push(error);
+                Instruction::Noop
+            }))
+            .collect();
+        if let Err(error) = validate_targets(&instructions, self) {
+            errors.extend(error.errors().iter().cloned());
+        }
+        errors.sort_by_key(InterpretError::line);
+        InterpretError::collect(errors)?;
+        self.program_len = instructions.len();
+        Ok(instructions)
+    }
+
+    /// The value of a register or immediate operand.
+    pub fn value(&self, operand: &Operand) -> Wrapping<u64> {
+        match operand {
+            Operand::Reg(reg) => self[reg],
+            Operand::Imm(value) => *value,
+        }
+    }
+
+    /// The count of a shift or rotation, an immediate or the value of a register.
+    fn shift_amount(&self, amount: &Operand) -> u64 {
+        match amount {
+            Operand::Reg(reg) => self[reg].0,
+            Operand::Imm(value) => value.0,
+        }
+    }
+
+    /// Reads register `reg` as the divisor of the division at `index`, which must not be zero.
+    fn divisor(&self, reg: &Register, index: usize) -> Result<u64, InterpretError> {
+        match self[reg].0 {
+            0 => Err(InterpretError::DivideByZero { line: index + 1 }),
+            divisor => Ok(divisor),
+        }
+    }
+
+    /// Whether the signed comparison jump `instruction` is taken after `cmp`.
+    fn signed_condition(&self, instruction: &Instruction) -> bool {
+        match instruction {
+            Instruction::Jg { .. } => !self.zero && self.sign == self.overflow,
+            Instruction::Jl { .. } => self.sign != self.overflow,
+            Instruction::Jge { .. } => self.sign == self.overflow,
+            Instruction::Jle { .. } => self.zero || self.sign != self.overflow,
+            _ => false,
+        }
+    }
+
+    fn with_zero(&mut self, reg: &Register, value: Wrapping<u64>) {
+        let value = value & Wrapping(self.register_mask());
+        self[reg] = value;
+        self.zero = value.0 == 0;
+    }
+
+    /// Like `with_zero` for the result of an `overflowing_*` operation, also setting the carry flag. The carry is also
+    /// set if the result doesn't fit in the register width.
+    fn with_carry(&mut self, reg: &Register, (value, carry): (u64, bool)) {
+        self.with_zero(reg, Wrapping(value));
+        self.carry = carry || value > self.register_mask();
+    }
+
+    /// FNV-1a hash of the registers, flags, stack pointer and the number of writes to memory, used to recognize a
+    /// machine state seen before. Memory that was written is never the same as before, even if the value did not
+    /// change.
+    pub fn fingerprint(&self) -> u64 {
+        let flags = [self.zero as u8, self.carry as u8, self.sign as u8, self.overflow as u8];
+        let words = self.registers.iter().map(|word| word.0).chain([self.sp as u64, self.memory_generation]);
+        fnv1a(flags.into_iter().chain(words.flat_map(u64::to_le_bytes)))
+    }
+
+    /// FNV-1a hash of the little-endian bytes of every register but `skip`, taken in register order.
+    pub fn checksum(&self, skip: Register) -> u64 {
+        fnv1a(self.registers.iter().enumerate().filter(|&(i, _)| i != skip).flat_map(|(_, r)| r.0.to_le_bytes()))
+    }
+
+    /// Prints the flags and a table of the registers to stdout.
+    pub fn dump(&self) -> io::Result<()> {
+        self.write_dump(&mut io::stdout())
+    }
+
+    /// The name of register `i` in dumps.
+    fn dump_name(&self, i: usize) -> String {
+        format!("R{}", i + self.config.one_based_regs as usize)
+    }
+
+    /// `value` in `Config::dump_radix`, padded with zeros to the register width unless it is decimal.
+    fn dump_value(&self, value: u64) -> String {
+        let width = self.config.width as usize;
+        match self.config.dump_radix {
+            2 => format!("0b{:0width$b}", value),
+            8 => format!("0o{:0digits$o}", value, digits = width.div_ceil(3)),
+            16 => format!("0x{:0digits$X}", value, digits = width / 4),
+            _ => value.to_string(),
+        }
+    }
+
+    /// Writes the flags and a table of the registers to `out`, followed by the first `Config::dump_memory` memory
+    /// words if there are any.
+    pub fn write_dump(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "Zero: {} Carry: {} Sign: {} Overflow: {}", self.zero, self.carry, self.sign, self.overflow)?;
+        writeln!(out, "SP: {}", self.sp)?;
+        self.write_table(out, self.registers.iter().enumerate().map(|(i, value)| (self.dump_name(i), *value)).collect())?;
+        match self.config.dump_memory.min(self.memory.len()) {
+            0 => Ok(()),
+            words => self.write_memory(out, 0..words),
+        }
+    }
+
+    /// Writes a table of the memory words in `range`, which must be inside memory, to `out`, in the same columns
+    /// as the registers.
+    pub fn write_memory(&self, out: &mut dyn Write, range: Range<usize>) -> io::Result<()> {
+        self.write_table(out, range.map(|addr| (format!("M{}", addr), self.memory[addr])).collect())
+    }
+
+    /// Writes a row for every `(name, value)` in `rows` in the layout of `Config::dump_format`. Every column is as
+    /// wide as its widest possible value, so the rows line up for any contents.
+    fn write_table(&self, out: &mut dyn Write, rows: Vec<(String, Wrapping<u64>)>) -> io::Result<()> {
+        let names = rows.iter().map(|(name, _)| name.len() + 2).max().unwrap_or(0).max(5);
+        let digits = self.config.width as usize / 4;
+        match self.config.dump_format {
+            DumpFormat::TableWide => {
+                let title = match self.config.dump_radix {
+                    2 => "binary",
+                    8 => "octal",
+                    16 => "hex",
+                    _ => "unsigned",
+                };
+                let first = self.dump_value(self.register_mask()).len().max(title.len());
+                let signed = self.signed(1 << (self.config.width - 1)).to_string().len().max("signed".len());
+                let hex = |value: &str| match self.config.dump_radix {
+                    16 => String::new(),
+                    _ => format!("  {:>hex$}", value, hex = digits + 2),
+                };
+                writeln!(out, "{:names$}{:>first$}  {:>signed$}{}", "", title, "signed", hex("hex"))?;
+                for (name, value) in rows {
+                    let row_hex = hex(&format!("0x{:0digits$X}", value));
+                    writeln!(out, "{:names$}{:>first$}  {:>signed$}{}", name + ":", self.dump_value(value.0), self.signed(value.0), row_hex)?;
+                }
+            },
+            DumpFormat::TableNarrow => {
+                writeln!(out, "{:names$}{:>hex$}", "", "hex", hex = digits + 2)?;
+                for (name, value) in rows {
+                    writeln!(out, "{:names$}0x{:0digits$X}", name + ":", value)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Serializes the registers, flags and the words of memory that are not zero, by address, tagged with the name of
+    /// the program if `source` is given.
+    pub fn dump_json(&self, source: Option<&str>) -> String {
+        let registers = self.registers.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(", ");
+        let memory = self.memory.iter()
+            .enumerate()
+            .filter(|(_, word)| word.0 != 0)
+            .map(|(addr, word)| format!("\"{}\": {}", addr, word))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let source = source.map(|source| format!("\"source\": \"{}\", ", json::escape(source))).unwrap_or_default();
+        format!("{{{}\"registers\": [{}], \"zero\": {}, \"memory\": {{{}}}}}", source, registers, self.zero, memory)
+    }
+
+    /// Serializes the registers, all flags and the program counter `pc` the program stopped at, for scripts checking
+    /// the result of a run, labelled with `source` like `dump_json`.
+    pub fn result_json(&self, pc: usize, source: Option<&str>) -> String {
+        let registers = self.registers.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(", ");
+        let source = source.map(|source| format!("\"source\": \"{}\", ", json::escape(source))).unwrap_or_default();
+        format!(
+            "{{{}\"registers\": [{}], \"zero\": {}, \"carry\": {}, \"sign\": {}, \"overflow\": {}, \"pc\": {}}}",
+            source, registers, self.zero, self.carry, self.sign, self.overflow, pc,
+        )
+    }
+
+    /// Reads registers, flags and memory in the format written by `dump_json`. Files without `memory` leave the memory
+    /// as it is.
+    pub fn load_json(&mut self, src: &str) -> Option<()> {
+        let json = json::parse(src)?;
+        let registers = json.get("registers")?.as_array()?;
+        if registers.len() != self.registers.len() {
+            return None;
+        }
+        for (i, value) in registers.iter().enumerate() {
+            self[&i] = Wrapping(value.as_u64()?);
+        }
+        self.zero = json.get("zero")?.as_bool()?;
+        if let Some(memory) = json.get("memory") {
+            for (addr, value) in memory.as_object()? {
+                let addr = addr.parse::<usize>().ok().filter(|&addr| addr < self.memory.len())?;
+                self.memory[addr] = Wrapping(value.as_u64()?);
+                self.memory_generation += 1;
+            }
+        }
+        Some(())
+    }
+
+    /// Describes every register and flag that differs from `expected`, one line each.
+    pub fn diff(&self, expected: &State) -> Vec<String> {
+        let mut diff = Vec::new();
+        if self.zero != expected.zero {
+            diff.push(format!("Zero: expected {}, got {}", expected.zero, self.zero));
+        }
+        for (i, (got, expected)) in self.registers.iter().zip(&expected.registers).enumerate() {
+            if got != expected {
+                diff.push(format!("R{}: expected {}, got {}", i, expected, got));
+            }
+        }
+        diff
+    }
+
+    /// The labels ordered by the index they point to and then by name, so listings don't depend on `HashMap` order.
+    pub fn sorted_labels(&self) -> Vec<(&str, usize)> {
+        let mut labels: Vec<_> = self.labels.iter().map(|(label, &index)| (label.as_str(), index)).collect();
+        labels.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(b.0)));
+        labels
+    }
+
+    pub fn write_labels(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (label, index) in self.sorted_labels() {
+            writeln!(out, "{}: line {}", label, index + 1)?;
+        }
+        let mut data_labels: Vec<_> = self.data_labels.iter().collect();
+        data_labels.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+        for (label, address) in data_labels {
+            writeln!(out, "{}: address {}", label, address)?;
+        }
+        Ok(())
+    }
+
+    pub fn dump_compact(&self) -> io::Result<()> {
+        self.write_dump_compact(&mut io::stdout())
+    }
+
+    pub fn write_dump_compact(&self, out: &mut dyn Write) -> io::Result<()> {
+        let registers = (0..self.registers.len()).map(|i| format!("{}={}", self.dump_name(i), self[&i])).collect::<Vec<_>>().join(" ");
+        writeln!(out, "Zero: {} Carry: {} Sign: {} Overflow: {} {}", self.zero, self.carry, self.sign, self.overflow, registers)
+    }
+}
+
+impl Index<&usize> for State {
+    type Output = Wrapping<u64>;
+
+    fn index(&self, index: &usize) -> &Self::Output {
+        &self.registers[*index]
+    }
+}
+
+impl IndexMut<&usize> for State {
+    fn index_mut(&mut self, index: &usize) -> &mut Self::Output {
+        &mut self.registers[*index]
+    }
+}
+
+/// An operand which is either a register or an immediate value written in the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg(Register),
+    Imm(Wrapping<u64>),
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => write!(f, "r{}", reg),
+            Operand::Imm(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// A condition on the flags which an instruction can be prefixed with, e.g. `zf: add r0, r1, r2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Predicate {
+    Zero,
+    NotZero,
+}
+
+impl Predicate {
+    fn from_name(name: &str) -> Option<Predicate> {
+        match name {
+            "zf" => Some(Predicate::Zero),
+            "nzf" => Some(Predicate::NotZero),
+            _ => None,
+        }
+    }
+
+    pub fn holds(self, state: &State) -> bool {
+        match self {
+            Predicate::Zero => state.zero,
+            Predicate::NotZero => !state.zero,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Predicate::Zero => "zero flag set",
+            Predicate::NotZero => "zero flag clear",
+        }
+    }
+}
+
+impl Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Predicate::Zero => "zf",
+            Predicate::NotZero => "nzf",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    Noop,
+    /// Stops at the debug prompt, unless `--debug-only` is given without its tag.
+    Debug {
+        tag: Option<String>,
+    },
+    Zero {
+        reg: Register,
+    },
+    Mov {
+        to: Register,
+        from: Register,
+    },
+    Add {
+        to: Register,
+        op1: Register,
+        op2: Operand,
+    },
+    Sub {
+        to: Register,
+        op1: Register,
+        op2: Operand,
+    },
+    Inc {
+        reg: Register,
+    },
+    Dec {
+        reg: Register,
+    },
+    And {
+        to: Register,
+        op1: Register,
+        op2: Operand,
+    },
+    Or {
+        to: Register,
+        op1: Register,
+        op2: Operand,
+    },
+    Xor {
+        to: Register,
+        op1: Register,
+        op2: Operand,
+    },
+    Not {
+        reg: Register,
+    },
+    Shl {
+        reg: Register,
+        amount: Operand,
+    },
+    Shr {
+        reg: Register,
+        amount: Operand,
+    },
+    Jz {
+        label: Label,
+    },
+    Jnz {
+        label: Label,
+    },
+    Jc {
+        label: Label,
+    },
+    Jg {
+        label: Label,
+    },
+    Jl {
+        label: Label,
+    },
+    Jge {
+        label: Label,
+    },
+    Jle {
+        label: Label,
+    },
+    Jnc {
+        label: Label,
+    },
+    /// Sets the flags from `op1 - op2` without writing a register.
+    Cmp {
+        op1: Register,
+        op2: Register,
+    },
+    J {
+        label: Label,
+    },
+    Steps {
+        reg: Register,
+    },
+    Loopn {
+        counter: Register,
+        limit: Register,
+        label: Label,
+    },
+    Paddb {
+        to: Register,
+        op1: Register,
+        op2: Register,
+    },
+    Pcmpeqb {
+        to: Register,
+        op1: Register,
+        op2: Register,
+    },
+    Sxtb {
+        reg: Register,
+    },
+    Sxtw {
+        reg: Register,
+    },
+    Sxtl {
+        reg: Register,
+    },
+    Zxtb {
+        reg: Register,
+    },
+    Zxtw {
+        reg: Register,
+    },
+    Zxtl {
+        reg: Register,
+    },
+    Minmax {
+        a: Register,
+        b: Register,
+    },
+    Rolr {
+        reg: Register,
+        by: Register,
+    },
+    Rorr {
+        reg: Register,
+        by: Register,
+    },
+    Split {
+        hi: Register,
+        lo: Register,
+        src: Register,
+    },
+    Join {
+        to: Register,
+        hi: Register,
+        lo: Register,
+    },
+    Load {
+        to: Register,
+        addr: Register,
+    },
+    Store {
+        addr: Register,
+        from: Register,
+    },
+    Clamp {
+        reg: Register,
+        lo: Register,
+        hi: Register,
+    },
+    Incm {
+        addr: Register,
+    },
+    Decm {
+        addr: Register,
+    },
+    Abs {
+        to: Register,
+        from: Register,
+    },
+    Checksum {
+        reg: Register,
+    },
+    /// Executes `inner` only when `predicate` holds, otherwise does nothing.
+    Predicated {
+        predicate: Predicate,
+        inner: Box<Instruction>,
+    },
+    Proglen {
+        reg: Register,
+    },
+    Sumregs {
+        reg: Register,
+    },
+    Xorregs {
+        reg: Register,
+    },
+    Push {
+        from: Register,
+    },
+    Pop {
+        to: Register,
+    },
+    Call {
+        label: Label,
+    },
+    Ret,
+    /// Skips the next instruction if the zero flag is set.
+    Skipz,
+    /// Skips the next instruction if the zero flag is clear.
+    Skipnz,
+    Callz {
+        label: Label,
+    },
+    Callnz {
+        label: Label,
+    },
+    Rothalf {
+        reg: Register,
+    },
+    Time {
+        reg: Register,
+    },
+    Jempty {
+        label: Label,
+    },
+    Jnempty {
+        label: Label,
+    },
+    Countif {
+        acc: Register,
+        a: Register,
+        b: Register,
+    },
+    Modexp {
+        to: Register,
+        base: Register,
+        exp: Register,
+        modulus: Register,
+    },
+    Movi {
+        to: Register,
+        value: u64,
+    },
+    Mulsat {
+        to: Register,
+        op1: Register,
+        op2: Register,
+    },
+    /// Writes the low 64 bits of the product to `lo` and, if given, the high 64 bits to `hi`.
+    Mul {
+        hi: Option<Register>,
+        lo: Register,
+        op1: Register,
+        op2: Register,
+    },
+    Div {
+        to: Register,
+        op1: Register,
+        op2: Register,
+    },
+    Mod {
+        to: Register,
+        op1: Register,
+        op2: Register,
+    },
+    Idiv {
+        to: Register,
+        op1: Register,
+        op2: Register,
+    },
+    Imod {
+        to: Register,
+        op1: Register,
+        op2: Register,
+    },
+    Rbit {
+        reg: Register,
+    },
+    Seteqi {
+        to: Register,
+        src: Register,
+        imm: u64,
+    },
+    Setlti {
+        to: Register,
+        src: Register,
+        imm: u64,
+    },
+    Setgti {
+        to: Register,
+        src: Register,
+        imm: u64,
+    },
+    Neg {
+        reg: Register,
+    },
+    Paddw {
+        to: Register,
+        op1: Register,
+        op2: Register,
+    },
+    Rol {
+        reg: Register,
+        amount: Operand,
+    },
+    Ror {
+        reg: Register,
+        amount: Operand,
+    },
+    Parityof {
+        to: Register,
+        from: Register,
+    },
+    Out {
+        reg: Register,
+    },
+    Putc {
+        reg: Register,
+    },
+    Loadd {
+        to: Register,
+        base: Register,
+        disp: u64,
+    },
+    Stored {
+        from: Register,
+        base: Register,
+        disp: u64,
+    },
+    In {
+        reg: Register,
+    },
+    Memset {
+        addr: Register,
+        val: Register,
+        len: Register,
+    },
+    Cmovc {
+        to: Register,
+        from: Register,
+    },
+    Cmovnc {
+        to: Register,
+        from: Register,
+    },
+    Isqrt {
+        to: Register,
+        from: Register,
+    },
+    Popcntall {
+        reg: Register,
+    },
+    Jsign {
+        reg: Register,
+        neg: Label,
+        zero: Label,
+        pos: Label,
+    },
+    Cas {
+        addr: Register,
+        expected: Register,
+        new: Register,
+    },
+    Snapshot {
+        tag: String,
+    },
+    Halteq {
+        a: Register,
+        b: Register,
+    },
+    Jr {
+        reg: Register,
+    },
+    Lea {
+        reg: Register,
+        label: Label,
+    },
+    Lut {
+        to: Register,
+        idx: Register,
+        values: Vec<u64>,
+    },
+    Cmovz {
+        to: Register,
+        from: Register,
+    },
+    Cmovnz {
+        to: Register,
+        from: Register,
+    },
+    /// Sets the zero flag from `op1 & op2` without writing a register.
+    Test {
+        op1: Register,
+        op2: Register,
+    },
+    /// Shifts `from` left until its highest set bit is the top bit of the register.
+    Normalize {
+        to: Register,
+        from: Register,
+    },
+    /// Reads a line into memory at `addr`, writing at most `len` words including the terminating 0, and replaces
+    /// `len` by the number of bytes stored.
+    Readline {
+        addr: Register,
+        len: Register,
+    },
+    /// Ends the program, with the low 8 bits of `code` as the exit code if given.
+    Halt {
+        code: Option<Register>,
+    },
+    /// Sets `to` to the position of the highest set bit of `from`, or 0 with the zero flag set if there is none.
+    Bsr {
+        to: Register,
+        from: Register,
+    },
+    /// Like `Bsr` for the lowest set bit.
+    Bsf {
+        to: Register,
+        from: Register,
+    },
+}
+
+impl Instruction {
+    /// Executes the instruction at `index` and returns the index of the next one. Anything the instruction reads,
+    /// including commands at the debug prompt, comes from `input` and anything it prints goes to `output`.
+    pub fn apply(&self, state: &mut State, index: usize, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<usize, InterpretError> {
+        match self {
+            Instruction::Noop => (),
+            Instruction::Debug { tag } => {
+                if !state.config.debug_enabled(tag.as_deref()) {
+                    return Ok(index + 1);
+                }
+                writeln!(output, "{} {}", ansi_term::Color::Yellow.paint("Debug:"), ansi_term::Color::Blue.paint(format!("line {}", index + 1))).map_err(io_error(index))?;
+                state.write_dump(output).map_err(io_error(index))?;
+                if state.config.sandbox {
+                    return Ok(index + 1);
+                }
+                if let Some(pc) = debug_prompt(state, index, false, input, output)? {
+                    return Ok(pc);
+                }
+            }
+            Instruction::Zero { reg } => state[reg] = Wrapping(0),
+            Instruction::Mov { to, from } => state[to] = state[from],
+            Instruction::Add { to, op1, op2 } => state.with_carry(to, state[op1].0.overflowing_add(state.value(op2).0)),
+            Instruction::Sub { to, op1, op2 } => state.with_carry(to, state[op1].0.overflowing_sub(state.value(op2).0)),
+            Instruction::Inc { reg } => state.with_carry(reg, state[reg].0.overflowing_add(1)),
+            Instruction::Dec { reg } => state.with_carry(reg, state[reg].0.overflowing_sub(1)),
+            Instruction::And { to, op1, op2 } => state.with_zero(to, state[op1] & state.value(op2)),
+            Instruction::Or { to, op1, op2 } => state.with_zero(to, state[op1] | state.value(op2)),
+            Instruction::Xor { to, op1, op2 } => state.with_zero(to, state[op1] ^ state.value(op2)),
+            Instruction::Not { reg } => state.with_zero(reg, !state[reg]),
+            Instruction::Shl { reg, amount } => {
+                let amount = state.shift_amount(amount);
+                state[reg] = Wrapping(u32::try_from(amount).ok().and_then(|amount| state[reg].0.checked_shl(amount)).unwrap_or(0));
+            },
+            Instruction::Shr { reg, amount } => {
+                let amount = state.shift_amount(amount);
+                state[reg] = Wrapping(u32::try_from(amount).ok().and_then(|amount| state[reg].0.checked_shr(amount)).unwrap_or(0));
+            },
+            Instruction::Jz { label } => if state.zero { return state.resolve_label(label, index) },
+            Instruction::Jnz { label } => if !state.zero { return state.resolve_label(label, index) },
+            Instruction::Jc { label } => if state.carry { return state.resolve_label(label, index) },
+            Instruction::Jg { label } | Instruction::Jl { label } | Instruction::Jge { label } | Instruction::Jle { label } => if state.signed_condition(self) {
+                return state.resolve_label(label, index);
+            },
+            Instruction::Cmp { op1, op2 } => {
+                let (a, b) = (state[op1].0, state[op2].0);
+                let difference = a.wrapping_sub(b) & state.register_mask();
+                state.zero = difference == 0;
+                state.carry = a < b;
+                state.sign = state.signed(difference) < 0;
+                state.overflow = i128::from(state.signed(a)) - i128::from(state.signed(b)) != i128::from(state.signed(difference));
+            },
+            Instruction::Jnc { label } => if !state.carry { return state.resolve_label(label, index) },
+            Instruction::J { label } => return state.resolve_label(label, index),
+            Instruction::Steps { reg } => state.with_zero(reg, Wrapping(state.steps)),
+            Instruction::Paddb { to, op1, op2 } => state.with_zero(to, Wrapping(lanewise(state[op1].0, state[op2].0, 8, u64::wrapping_add))),
+            Instruction::Pcmpeqb { to, op1, op2 } => state.with_zero(to, Wrapping(lanewise(state[op1].0, state[op2].0, 8, |a, b| if a == b { u64::MAX } else { 0 }))),
+            Instruction::Sxtb { reg } => state.with_zero(reg, Wrapping(state.sign_extend(state[reg].0, 8))),
+            Instruction::Sxtw { reg } => state.with_zero(reg, Wrapping(state.sign_extend(state[reg].0, 16))),
+            Instruction::Sxtl { reg } => state.with_zero(reg, Wrapping(state.sign_extend(state[reg].0, 32))),
+            Instruction::Zxtb { reg } => state.with_zero(reg, Wrapping(state[reg].0 & state.low_bits(8))),
+            Instruction::Zxtw { reg } => state.with_zero(reg, Wrapping(state[reg].0 & state.low_bits(16))),
+            Instruction::Zxtl { reg } => state.with_zero(reg, Wrapping(state[reg].0 & state.low_bits(32))),
+            Instruction::Minmax { a, b } => {
+                state.zero = state[a] <= state[b];
+                if !state.zero {
+                    state.registers.swap(*a, *b);
+                }
+            },
+            Instruction::Loopn { counter, limit, label } => {
+                state[counter] += Wrapping(1);
+                state.zero = state[counter] >= state[limit];
+                if !state.zero {
+                    return state.resolve_label(label, index);
+                }
+            },
+            Instruction::Rolr { reg, by } => state.with_zero(reg, Wrapping(state.rotate_left(state[reg].0, state[by].0))),
+            Instruction::Rorr { reg, by } => {
+                let width = state.config.width as u64;
+                state.with_zero(reg, Wrapping(state.rotate_left(state[reg].0, width - state[by].0 % width)));
+            },
+            Instruction::Split { hi, lo, src } => {
+                let (value, half) = (state[src].0, state.config.width / 2);
+                state[hi] = Wrapping(value >> half);
+                state.with_zero(lo, Wrapping(value & state.low_bits(half)));
+            },
+            Instruction::Join { to, hi, lo } => {
+                let half = state.config.width / 2;
+                state.with_zero(to, Wrapping(state[hi].0 << half | (state[lo].0 & state.low_bits(half))));
+            },
+            Instruction::Load { to, addr } => state[to] = state.load(state[addr], index)?,
+            Instruction::Store { addr, from } => state.store(state[addr], state[from], index)?,
+            Instruction::Clamp { reg, lo, hi } => {
+                if state[lo] > state[hi] {
+                    return Err(InterpretError::runtime(index, format!("clamp lower bound {} is greater than upper bound {} on line {}", state[lo], state[hi], index + 1)));
+                }
+                state.with_zero(reg, state[reg].clamp(state[lo], state[hi]));
+            },
+            Instruction::Incm { addr } => {
+                let value = state.load(state[addr], index)? + Wrapping(1);
+                state.store(state[addr], value, index)?;
+                state.zero = value.0 == 0;
+            },
+            Instruction::Decm { addr } => {
+                let value = state.load(state[addr], index)? - Wrapping(1);
+                state.store(state[addr], value, index)?;
+                state.zero = value.0 == 0;
+            },
+            Instruction::Abs { to, from } => {
+                let value = state.signed(state[from].0);
+                if value < -state.signed_max() && state.config.strict_overflow {
+                    return Err(InterpretError::runtime(index, format!("absolute value of {} overflows on line {}", value, index + 1)));
+                }
+                state.with_zero(to, Wrapping(value.unsigned_abs().min(state.signed_max() as u64)));
+            },
+            Instruction::Checksum { reg } => {
+                let checksum = state.checksum(*reg);
+                state.with_zero(reg, Wrapping(checksum));
+            },
+            Instruction::Predicated { predicate, inner } => if predicate.holds(state) {
+                return inner.apply(state, index, input, output);
+            },
+            Instruction::Proglen { reg } => state.with_zero(reg, Wrapping(state.program_len as u64)),
+            Instruction::Sumregs { reg } => state.with_zero(reg, state.registers.iter().sum()),
+            Instruction::Xorregs { reg } => state.with_zero(reg, state.registers.iter().fold(Wrapping(0), |acc, r| acc ^ r)),
+            Instruction::Push { from } => {
+                if state.sp <= state.stack_limit() {
+                    return Err(InterpretError::runtime(index, format!("stack overflow on line {}", index + 1)));
+                }
+                state.sp -= 1;
+                let sp = state.sp;
+                state.memory[sp] = state[from];
+                state.memory_generation += 1;
+                state.peak_stack_depth = state.peak_stack_depth.max(state.stack_base - sp);
+            },
+            Instruction::Pop { to } => {
+                if state.stack_is_empty() {
+                    return Err(InterpretError::runtime(index, format!("stack underflow on line {}", index + 1)));
+                }
+                state[to] = state.memory[state.sp];
+                state.sp += 1;
+            },
+            Instruction::Call { label } => return state.call(label, index),
+            Instruction::Skipz | Instruction::Skipnz => if state.zero == matches!(self, Instruction::Skipz) {
+                return Ok((index + 2).min(state.program_len));
+            },
+            Instruction::Ret => {
+                return state.call_stack.pop().ok_or_else(|| InterpretError::runtime(index, format!("return with an empty call stack on line {}", index + 1)));
+            },
+            Instruction::Callz { label } => if state.zero { return state.call(label, index) },
+            Instruction::Callnz { label } => if !state.zero { return state.call(label, index) },
+            Instruction::Rothalf { reg } => state.with_zero(reg, Wrapping(state.rotate_left(state[reg].0, state.config.width as u64 / 2))),
+            Instruction::Time { reg } => {
+                let millis = match state.config.sandbox {
+                    true => SANDBOX_TIME,
+                    false => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64),
+                };
+                state.with_zero(reg, Wrapping(millis));
+            },
+            Instruction::Jempty { label } => if state.stack_is_empty() { return state.resolve_label(label, index) },
+            Instruction::Jnempty { label } => if !state.stack_is_empty() { return state.resolve_label(label, index) },
+            Instruction::Countif { acc, a, b } => state.with_zero(acc, state[acc] + Wrapping((state[a] == state[b]) as u64)),
+            Instruction::Modexp { to, base, exp, modulus } => {
+                if state[modulus].0 == 0 {
+                    return Err(InterpretError::runtime(index, format!("modexp with a zero modulus on line {}", index + 1)));
+                }
+                state.with_zero(to, Wrapping(modpow(state[base].0, state[exp].0, state[modulus].0)));
+            },
+            Instruction::Movi { to, value } => state[to] = Wrapping(*value),
+            Instruction::Mulsat { to, op1, op2 } => state.with_zero(to, Wrapping(state[op1].0.saturating_mul(state[op2].0).min(state.register_mask()))),
+            Instruction::Mul { hi, lo, op1, op2 } => {
+                let product = state[op1].0 as u128 * state[op2].0 as u128;
+                state[lo] = Wrapping(product as u64 & state.register_mask());
+                if let Some(hi) = hi {
+                    state[hi] = Wrapping((product >> state.config.width) as u64 & state.register_mask());
+                }
+                state.zero = product == 0;
+            },
+            Instruction::Div { to, op1, op2 } => {
+                let divisor = state.divisor(op2, index)?;
+                state.with_zero(to, Wrapping(state[op1].0 / divisor));
+            },
+            Instruction::Mod { to, op1, op2 } => {
+                let divisor = state.divisor(op2, index)?;
+                state.with_zero(to, Wrapping(state[op1].0 % divisor));
+            },
+            Instruction::Idiv { to, op1, op2 } => {
+                let (dividend, divisor) = (state.signed(state[op1].0), state.signed(state.divisor(op2, index)?));
+                if dividend < -state.signed_max() && divisor == -1 && state.config.strict_overflow {
+                    return Err(InterpretError::runtime(index, format!("quotient of {} and -1 overflows on line {}", dividend, index + 1)));
+                }
+                state.with_zero(to, Wrapping(dividend.saturating_div(divisor).min(state.signed_max()) as u64));
+            },
+            Instruction::Imod { to, op1, op2 } => {
+                let divisor = state.signed(state.divisor(op2, index)?);
+                state.with_zero(to, Wrapping(state.signed(state[op1].0).wrapping_rem(divisor) as u64));
+            },
+            Instruction::Rbit { reg } => state.with_zero(reg, Wrapping(state[reg].0.reverse_bits() >> (64 - state.config.width))),
+            Instruction::Seteqi { to, src, imm } => state.with_zero(to, Wrapping((state[src].0 == *imm) as u64)),
+            Instruction::Setlti { to, src, imm } => state.with_zero(to, Wrapping((state[src].0 < *imm) as u64)),
+            Instruction::Setgti { to, src, imm } => state.with_zero(to, Wrapping((state[src].0 > *imm) as u64)),
+            Instruction::Neg { reg } => state.with_zero(reg, Wrapping(0) - state[reg]),
+            Instruction::Paddw { to, op1, op2 } => state.with_zero(to, Wrapping(lanewise(state[op1].0, state[op2].0, 16, u64::wrapping_add))),
+            Instruction::Rol { reg, amount } => state.with_zero(reg, Wrapping(state.rotate_left(state[reg].0, state.shift_amount(amount)))),
+            Instruction::Ror { reg, amount } => {
+                let width = state.config.width as u64;
+                state.with_zero(reg, Wrapping(state.rotate_left(state[reg].0, width - state.shift_amount(amount) % width)));
+            },
+            Instruction::Parityof { to, from } => state.with_zero(to, Wrapping((state[from].0.count_ones() % 2) as u64)),
+            Instruction::Out { reg } => {
+                writeln!(output, "{}", state[reg]).map_err(io_error(index))?;
+                output.flush().map_err(io_error(index))?;
+            },
+            Instruction::Putc { reg } => {
+                output.write_all(&[state[reg].0 as u8]).map_err(io_error(index))?;
+                output.flush().map_err(io_error(index))?;
+            },
+            Instruction::Loadd { to, base, disp } => state[to] = state.load(state[base] + Wrapping(*disp), index)?,
+            Instruction::Stored { from, base, disp } => state.store(state[base] + Wrapping(*disp), state[from], index)?,
+            Instruction::In { reg } => {
+                if state.config.sandbox {
+                    return Err(InterpretError::runtime(index, format!("`in` cannot read input in sandbox mode on line {}", index + 1)));
+                }
+                let mut line = String::new();
+                let value = match input.read_line(&mut line).map_err(io_error(index))? {
+                    0 => 0,
+                    _ => parse_value(&line).ok_or_else(|| InterpretError::runtime(index, format!("invalid input `{}` on line {}", line.trim(), index + 1)))?,
+                };
+                state.with_zero(reg, Wrapping(value));
+            },
+            Instruction::Memset { addr, val, len } => state.fill(state[addr], state[val], state[len].0, index)?,
+            Instruction::Cmovc { to, from } => if state.carry { state[to] = state[from] },
+            Instruction::Cmovnc { to, from } => if !state.carry { state[to] = state[from] },
+            Instruction::Isqrt { to, from } => state.with_zero(to, Wrapping(state[from].0.isqrt())),
+            Instruction::Popcntall { reg } => state.with_zero(reg, Wrapping(state.registers.iter().map(|r| r.0.count_ones() as u64).sum())),
+            Instruction::Jsign { reg, neg, zero, pos } => return state.resolve_label(match state.signed(state[reg].0).cmp(&0) {
+                std::cmp::Ordering::Less => neg,
+                std::cmp::Ordering::Equal => zero,
+                std::cmp::Ordering::Greater => pos,
+            }, index),
+            Instruction::Cas { addr, expected, new } => {
+                let value = state.load(state[addr], index)?;
+                state.zero = value == state[expected];
+                match state.zero {
+                    true => state.store(state[addr], state[new], index)?,
+                    false => state[expected] = value,
+                }
+            },
+            Instruction::Snapshot { tag } => {
+                let registers: Vec<_> = state.registers.iter().enumerate().map(|(i, value)| format!("r{}={}", i, value)).collect();
+                writeln!(output, "SNAPSHOT {} {}", tag, registers.join(" ")).map_err(io_error(index))?;
+            },
+            Instruction::Halteq { a, b } => if state[a] == state[b] { return Ok(state.program_len) },
+            Instruction::Jr { reg } => return match usize::try_from(state[reg].0).ok().filter(|&target| target <= state.program_len) {
+                Some(target) => Ok(target),
+                None => Err(InterpretError::runtime(index, format!("jump to {} out of range on line {}", state[reg], index + 1))),
+            },
+            Instruction::Lea { reg, label } => state[reg] = Wrapping(state.label_value(label, index)?),
+            Instruction::Lut { to, idx, values } => match usize::try_from(state[idx].0).ok().and_then(|i| values.get(i)) {
+                Some(&value) => state.with_zero(to, Wrapping(value)),
+                None => return Err(InterpretError::runtime(index, format!("table index {} out of range on line {}", state[idx], index + 1))),
+            },
+            Instruction::Cmovz { to, from } => if state.zero { state[to] = state[from] },
+            Instruction::Cmovnz { to, from } => if !state.zero { state[to] = state[from] },
+            Instruction::Test { op1, op2 } => state.zero = (state[op1] & state[op2]).0 == 0,
+            Instruction::Normalize { to, from } => {
+                let value = state[from].0;
+                let top = value.checked_shl(value.leading_zeros()).unwrap_or(0) >> (64 - state.config.width);
+                state.with_zero(to, Wrapping(top));
+            },
+            Instruction::Readline { addr, len } => {
+                if state.config.sandbox {
+                    return Err(InterpretError::runtime(index, format!("`readline` cannot read input in sandbox mode on line {}", index + 1)));
+                }
+                let mut line = String::new();
+                input.read_line(&mut line).map_err(io_error(index))?;
+                let line = line.strip_suffix('\n').unwrap_or(&line);
+                let line = line.strip_suffix('\r').unwrap_or(line);
+                // One word is kept for the terminating 0.
+                let bytes: Vec<u8> = line.bytes().take((state[len].0 as usize).saturating_sub(1)).collect();
+                if state[len].0 > 0 {
+                    for (i, byte) in bytes.iter().chain(&[0]).enumerate() {
+                        state.store(state[addr] + Wrapping(i as u64), Wrapping(u64::from(*byte)), index)?;
+                    }
+                }
+                state.with_zero(len, Wrapping(bytes.len() as u64));
+            },
+            Instruction::Halt { code } => {
+                // A program that is not trusted doesn't get to pick the exit code.
+                state.exit_code = code.filter(|_| !state.config.sandbox).map(|reg| state[&reg].0 as u8);
+                return Ok(state.program_len);
+            },
+            Instruction::Bsr { to, from } => {
+                let value = state[from].0;
+                state[to] = Wrapping(if value == 0 { 0 } else { u64::from(63 - value.leading_zeros()) });
+                state.zero = value == 0;
+            },
+            Instruction::Bsf { to, from } => {
+                let value = state[from].0;
+                state[to] = Wrapping(if value == 0 { 0 } else { u64::from(value.trailing_zeros()) });
+                state.zero = value == 0;
+            },
+        }
+        Ok(index + 1)
+    }
+
+    /// The registers whose values the instruction reads on a machine with `registers` registers.
+    pub fn sources(&self, registers: usize) -> Vec<Register> {
+        match self {
+            Instruction::Noop | Instruction::Debug { .. } | Instruction::Zero { .. } | Instruction::Steps { .. }
+            | Instruction::Proglen { .. } | Instruction::Pop { .. } | Instruction::Time { .. } | Instruction::Movi { .. } | Instruction::In { .. }
+            | Instruction::Lea { .. } => vec![],
+            Instruction::Jz { .. } | Instruction::Jnz { .. } | Instruction::Jc { .. } | Instruction::Jnc { .. } | Instruction::J { .. }
+            | Instruction::Call { .. } | Instruction::Ret | Instruction::Skipz | Instruction::Skipnz | Instruction::Jg { .. } | Instruction::Jl { .. } | Instruction::Jge { .. } | Instruction::Jle { .. }
+            | Instruction::Callz { .. } | Instruction::Callnz { .. } | Instruction::Jempty { .. } | Instruction::Jnempty { .. } => vec![],
+            Instruction::Mov { from, .. } | Instruction::Jsign { reg: from, .. } => vec![*from],
+            Instruction::Add { op1, op2, .. } | Instruction::Sub { op1, op2, .. } | Instruction::And { op1, op2, .. }
+            | Instruction::Or { op1, op2, .. } | Instruction::Xor { op1, op2, .. } => match op2 {
+                Operand::Reg(op2) => vec![*op1, *op2],
+                Operand::Imm(_) => vec![*op1],
+            },
+            Instruction::Paddb { op1, op2, .. } | Instruction::Paddw { op1, op2, .. } | Instruction::Pcmpeqb { op1, op2, .. } | Instruction::Mulsat { op1, op2, .. }
+            | Instruction::Mul { op1, op2, .. } | Instruction::Cmp { op1, op2 } | Instruction::Test { op1, op2 } | Instruction::Div { op1, op2, .. } | Instruction::Mod { op1, op2, .. }
+            | Instruction::Idiv { op1, op2, .. } | Instruction::Imod { op1, op2, .. } => vec![*op1, *op2],
+            Instruction::Shl { reg, amount } | Instruction::Shr { reg, amount } | Instruction::Rol { reg, amount }
+            | Instruction::Ror { reg, amount } => match amount {
+                Operand::Reg(by) => vec![*reg, *by],
+                Operand::Imm(_) => vec![*reg],
+            },
+            Instruction::Inc { reg } | Instruction::Dec { reg } | Instruction::Not { reg } | Instruction::Sxtb { reg } | Instruction::Sxtw { reg } | Instruction::Sxtl { reg }
+            | Instruction::Zxtb { reg } | Instruction::Zxtw { reg } | Instruction::Zxtl { reg } | Instruction::Rothalf { reg } | Instruction::Rbit { reg } | Instruction::Neg { reg } => vec![*reg],
+            Instruction::Loopn { counter, limit, .. } => vec![*counter, *limit],
+            Instruction::Minmax { a, b } | Instruction::Halteq { a, b } => vec![*a, *b],
+            Instruction::Halt { code } => code.iter().copied().collect(),
+            Instruction::Rolr { reg, by } | Instruction::Rorr { reg, by } => vec![*reg, *by],
+            Instruction::Split { src, .. } | Instruction::Seteqi { src, .. } | Instruction::Setlti { src, .. }
+            | Instruction::Setgti { src, .. } => vec![*src],
+            Instruction::Abs { from, .. } | Instruction::Parityof { from, .. } | Instruction::Isqrt { from, .. }
+            | Instruction::Normalize { from, .. } | Instruction::Bsr { from, .. } | Instruction::Bsf { from, .. } => vec![*from],
+            Instruction::Join { hi, lo, .. } => vec![*hi, *lo],
+            Instruction::Load { addr, .. } | Instruction::Incm { addr } | Instruction::Decm { addr } => vec![*addr],
+            Instruction::Store { addr, from } | Instruction::Stored { from, base: addr, .. } => vec![*addr, *from],
+            Instruction::Loadd { base, .. } => vec![*base],
+            Instruction::Clamp { reg, lo, hi } => vec![*reg, *lo, *hi],
+            Instruction::Memset { addr, val, len } => vec![*addr, *val, *len],
+            Instruction::Readline { addr, len } => vec![*addr, *len],
+            Instruction::Cas { addr, expected, new } => vec![*addr, *expected, *new],
+            Instruction::Cmovc { to, from } | Instruction::Cmovnc { to, from } | Instruction::Cmovz { to, from }
+            | Instruction::Cmovnz { to, from } => vec![*to, *from],
+            Instruction::Countif { acc, a, b } => vec![*acc, *a, *b],
+            Instruction::Modexp { base, exp, modulus, .. } => vec![*base, *exp, *modulus],
+            Instruction::Checksum { reg } => (0..registers).filter(|r| r != reg).collect(),
+            Instruction::Push { from } => vec![*from],
+            Instruction::Out { reg } | Instruction::Putc { reg } | Instruction::Jr { reg } | Instruction::Lut { idx: reg, .. } => vec![*reg],
+            Instruction::Sumregs { .. } | Instruction::Xorregs { .. } | Instruction::Popcntall { .. } | Instruction::Snapshot { .. } => (0..registers).collect(),
+            Instruction::Predicated { inner, .. } => inner.sources(registers),
+        }
+    }
+
+    /// The registers the instruction may write.
+    pub fn destinations(&self) -> Vec<Register> {
+        match self {
+            Instruction::Noop | Instruction::Debug { .. } | Instruction::Store { .. } | Instruction::Incm { .. } | Instruction::Decm { .. }
+            | Instruction::Push { .. } | Instruction::Cmp { .. } | Instruction::Test { .. } | Instruction::Stored { .. } | Instruction::Out { .. } | Instruction::Putc { .. }
+            | Instruction::Memset { .. } | Instruction::Snapshot { .. } | Instruction::Halteq { .. } | Instruction::Halt { .. }
+            | Instruction::Jr { .. } => vec![],
+            Instruction::Jz { .. } | Instruction::Jnz { .. } | Instruction::Jc { .. } | Instruction::Jnc { .. } | Instruction::J { .. }
+            | Instruction::Call { .. } | Instruction::Ret | Instruction::Skipz | Instruction::Skipnz | Instruction::Jg { .. } | Instruction::Jl { .. } | Instruction::Jge { .. } | Instruction::Jle { .. }
+            | Instruction::Callz { .. } | Instruction::Callnz { .. } | Instruction::Jempty { .. } | Instruction::Jnempty { .. }
+            | Instruction::Jsign { .. } => vec![],
+            Instruction::Zero { reg } | Instruction::Steps { reg } | Instruction::Inc { reg } | Instruction::Dec { reg }
+            | Instruction::Not { reg } | Instruction::Shl { reg, .. } | Instruction::Shr { reg, .. } | Instruction::Sxtb { reg }
+            | Instruction::Sxtw { reg } | Instruction::Sxtl { reg } | Instruction::Zxtb { reg } | Instruction::Zxtw { reg }
+            | Instruction::Zxtl { reg } | Instruction::Rolr { reg, .. } | Instruction::Rorr { reg, .. }
+            | Instruction::Clamp { reg, .. } | Instruction::Checksum { reg } | Instruction::Proglen { reg }
+            | Instruction::Sumregs { reg } | Instruction::Xorregs { reg } | Instruction::Popcntall { reg } | Instruction::Rothalf { reg } | Instruction::Rbit { reg } | Instruction::Neg { reg }
+            | Instruction::Rol { reg, .. } | Instruction::Ror { reg, .. }
+            | Instruction::Time { reg } | Instruction::Countif { acc: reg, .. } | Instruction::Cas { expected: reg, .. }
+            | Instruction::Lea { reg, .. } | Instruction::Lut { to: reg, .. } => vec![*reg],
+            Instruction::Mov { to, .. } | Instruction::Add { to, .. } | Instruction::Sub { to, .. } | Instruction::And { to, .. }
+            | Instruction::Or { to, .. } | Instruction::Xor { to, .. } | Instruction::Paddb { to, .. } | Instruction::Paddw { to, .. }
+            | Instruction::Pcmpeqb { to, .. } | Instruction::Join { to, .. } | Instruction::Load { to, .. } | Instruction::Loadd { to, .. } | Instruction::In { reg: to }
+            | Instruction::Readline { len: to, .. } | Instruction::Abs { to, .. } | Instruction::Parityof { to, .. } | Instruction::Pop { to } | Instruction::Modexp { to, .. }
+            | Instruction::Movi { to, .. } | Instruction::Mulsat { to, .. } | Instruction::Div { to, .. }
+            | Instruction::Mod { to, .. } | Instruction::Idiv { to, .. } | Instruction::Imod { to, .. }
+            | Instruction::Seteqi { to, .. } | Instruction::Setlti { to, .. } | Instruction::Setgti { to, .. }
+            | Instruction::Cmovc { to, .. } | Instruction::Cmovnc { to, .. } | Instruction::Isqrt { to, .. }
+            | Instruction::Cmovz { to, .. } | Instruction::Cmovnz { to, .. } | Instruction::Normalize { to, .. }
+            | Instruction::Bsr { to, .. } | Instruction::Bsf { to, .. } => vec![*to],
+            Instruction::Loopn { counter, .. } => vec![*counter],
+            Instruction::Minmax { a, b } => vec![*a, *b],
+            Instruction::Split { hi, lo, .. } => vec![*hi, *lo],
+            Instruction::Mul { hi, lo, .. } => hi.iter().chain([lo]).copied().collect(),
+            Instruction::Predicated { inner, .. } => inner.destinations(),
+        }
+    }
+
+    /// Explains in words what executing the instruction at `index` did, with the values substituted, given the state
+    /// before and after it and the index of the next instruction. The zero flag is only mentioned when it changed.
+    pub fn explain(&self, before: &State, after: &State, next: usize, index: usize) -> String {
+        let line = |target: usize| if target == after.program_len { "the end".to_string() } else { format!("line {}", target + 1) };
+        let binary = |to: &Register, op1: &Register, op2: &Operand, op: &str| {
+            format!("r{} = r{} {} {} = {} {} {} = {}", to, op1, op, op2, before[op1], op, before.value(op2), after[to])
+        };
+        let unary = |reg: &Register, op: &str| format!("r{} = r{} {} = {} {} = {}", reg, reg, op, before[reg], op, after[reg]);
+        let branch = |taken: bool, condition: &str, action: &str| match taken {
+            true => format!("{}, {} to {}", condition, action, line(next)),
+            false => format!("{}, no {}", condition, action),
+        };
+        let carry_flag = |flag: bool| if flag { "carry flag set" } else { "carry flag clear" };
+        let zero_flag = |flag: bool| if flag { Predicate::Zero.description() } else { Predicate::NotZero.description() };
+        let explanation = match self {
+            Instruction::Noop => "nothing".to_string(),
+            Instruction::Debug { tag } if !before.config.debug_enabled(tag.as_deref()) => "nothing, the debug point is disabled".to_string(),
+            Instruction::Debug { .. } => "stop at the debug prompt".to_string(),
+            Instruction::Predicated { predicate, inner } => return match predicate.holds(before) {
+                true => format!("condition `{}` holds, {}", predicate, inner.explain(before, after, next, index)),
+                false => format!("condition `{}` does not hold, skipped", predicate),
+            },
+            Instruction::Zero { reg } => format!("r{} = 0", reg),
+            Instruction::Mov { to, from } => format!("r{} = r{} = {}", to, from, after[to]),
+            Instruction::Normalize { to, from } => format!("r{} = normalize r{} = {}", to, from, after[to]),
+            Instruction::Add { to, op1, op2 } => binary(to, op1, op2, "+"),
+            Instruction::Sub { to, op1, op2 } => binary(to, op1, op2, "-"),
+            Instruction::And { to, op1, op2 } => binary(to, op1, op2, "&"),
+            Instruction::Or { to, op1, op2 } => binary(to, op1, op2, "|"),
+            Instruction::Xor { to, op1, op2 } => binary(to, op1, op2, "^"),
+            Instruction::Mulsat { to, op1, op2 } | Instruction::Mul { hi: None, lo: to, op1, op2 } => binary(to, op1, &Operand::Reg(*op2), "*"),
+            Instruction::Div { to, op1, op2 } => binary(to, op1, &Operand::Reg(*op2), "/"),
+            Instruction::Mod { to, op1, op2 } => binary(to, op1, &Operand::Reg(*op2), "%"),
+            Instruction::Inc { reg } => unary(reg, "+ 1"),
+            Instruction::Dec { reg } => unary(reg, "- 1"),
+            Instruction::Shl { reg, amount } => unary(reg, &format!("<< {}", before.shift_amount(amount))),
+            Instruction::Rol { reg, amount } => unary(reg, &format!("rotated left by {}", before.shift_amount(amount) % before.config.width as u64)),
+            Instruction::Ror { reg, amount } => unary(reg, &format!("rotated right by {}", before.shift_amount(amount) % before.config.width as u64)),
+            Instruction::Shr { reg, amount } => unary(reg, &format!(">> {}", before.shift_amount(amount))),
+            Instruction::Not { reg } => format!("r{} = !r{} = !{} = {}", reg, reg, before[reg], after[reg]),
+            Instruction::Neg { reg } => format!("r{} = -r{} = -{} = {}", reg, reg, before[reg], after[reg]),
+            Instruction::Jz { .. } => branch(before.zero, zero_flag(before.zero), "jump"),
+            Instruction::Jnz { .. } => branch(!before.zero, zero_flag(before.zero), "jump"),
+            Instruction::Jc { .. } => branch(before.carry, carry_flag(before.carry), "jump"),
+            Instruction::Jg { .. } | Instruction::Jl { .. } | Instruction::Jge { .. } | Instruction::Jle { .. } => {
+                let condition = self.condition().unwrap_or_default();
+                branch(before.signed_condition(self), &format!("{}: sign {}, overflow {}", condition, before.sign, before.overflow), "jump")
+            },
+            Instruction::Cmp { op1, op2 } => format!("compare r{} = {} with r{} = {}", op1, before[op1], op2, before[op2]),
+            Instruction::Test { op1, op2 } => format!("test r{} = {} & r{} = {} for zero", op1, before[op1], op2, before[op2]),
+            Instruction::Jnc { .. } => branch(!before.carry, carry_flag(before.carry), "jump"),
+            Instruction::J { .. } => format!("jump to {}", line(next)),
+            Instruction::Loopn { counter, limit, .. } => {
+                let comparison = if after[counter] < before[limit] { "<" } else { ">=" };
+                let condition = format!("r{} = r{} + 1 = {} {} r{} = {}", counter, counter, after[counter], comparison, limit, before[limit]);
+                branch(comparison == "<", &condition, "jump")
+            },
+            Instruction::Call { .. } => format!("call {}, returning to {}", line(next), line(index + 1)),
+            Instruction::Callz { .. } => branch(before.zero, zero_flag(before.zero), "call"),
+            Instruction::Callnz { .. } => branch(!before.zero, zero_flag(before.zero), "call"),
+            Instruction::Ret => format!("return to {}", line(next)),
+            Instruction::Skipz => branch(before.zero, zero_flag(before.zero), "skip"),
+            Instruction::Skipnz => branch(!before.zero, zero_flag(before.zero), "skip"),
+            Instruction::Jempty { .. } | Instruction::Jnempty { .. } => {
+                let condition = if before.stack_is_empty() { "stack empty" } else { "stack not empty" };
+                branch(before.stack_is_empty() == matches!(self, Instruction::Jempty { .. }), condition, "jump")
+            },
+            Instruction::Jsign { reg, .. } => {
+                let sign = match before.signed(before[reg].0).cmp(&0) {
+                    std::cmp::Ordering::Less => "negative",
+                    std::cmp::Ordering::Equal => "zero",
+                    std::cmp::Ordering::Greater => "positive",
+                };
+                format!("r{} = {} is {}, jump to {}", reg, before[reg], sign, line(next))
+            },
+            Instruction::Load { to, addr } => format!("r{} = memory[r{}] = memory[{}] = {}", to, addr, before[addr], after[to]),
+            Instruction::Store { addr, from } => format!("memory[r{}] = memory[{}] = r{} = {}", addr, before[addr], from, before[from]),
+            Instruction::Halteq { a, b } => match before[a] == before[b] {
+                true => format!("r{} = {} equals r{}, halt", a, before[a], b),
+                false => format!("r{} = {} differs from r{} = {}, no halt", a, before[a], b, before[b]),
+            },
+            Instruction::Halt { code: Some(reg) } => format!("halt with exit code r{} = {}", reg, before[reg].0 as u8),
+            Instruction::Halt { code: None } => "halt".to_string(),
+            Instruction::Jr { reg } => format!("jump to r{} = {}", reg, line(next)),
+            Instruction::Lut { to, idx, .. } => format!("r{} = entry r{} = {} of the table = {}", to, idx, before[idx], after[to]),
+            Instruction::Lea { reg, label } => {
+                let what = if before.data_address(label).is_some() { "address" } else { "index" };
+                format!("r{} = {} of {} = {}", reg, what, label, after[reg])
+            },
+            Instruction::Snapshot { tag } => format!("print the registers tagged `{}`", tag),
+            Instruction::Cas { addr, expected, new } => match after.zero {
+                true => format!("memory[{}] equals r{} = {}, memory[{}] = r{} = {}", before[addr], expected, before[expected], before[addr], new, before[new]),
+                false => format!("memory[{}] = {} differs from r{} = {}, r{} = {}", before[addr], after[expected], expected, before[expected], expected, after[expected]),
+            },
+            Instruction::Loadd { to, base, disp } => {
+                format!("r{} = memory[r{} + {}] = memory[{}] = {}", to, base, *disp as i64, before[base] + Wrapping(*disp), after[to])
+            },
+            Instruction::Stored { from, base, disp } => {
+                format!("memory[r{} + {}] = memory[{}] = r{} = {}", base, *disp as i64, before[base] + Wrapping(*disp), from, before[from])
+            },
+            Instruction::Memset { addr, val, len } => {
+                format!("memory[{}..{}] = r{} = {}", before[addr], before[addr] + before[len], val, before[val])
+            },
+            Instruction::Readline { addr, len } => {
+                format!("memory[{}..{}] = line of r{} = {} bytes", before[addr], before[addr] + after[len], len, after[len])
+            },
+            Instruction::Incm { addr } | Instruction::Decm { addr } => {
+                let op = if matches!(self, Instruction::Incm { .. }) { "+" } else { "-" };
+                format!("memory[{}] = memory[{}] {} 1 = {}", before[addr], before[addr], op, after.load(before[addr], index).unwrap_or_default())
+            },
+            Instruction::Push { from } => format!("push r{} = {}, stack pointer {} -> {}", from, before[from], before.sp, after.sp),
+            Instruction::Pop { to } => format!("pop r{} = {}, stack pointer {} -> {}", to, after[to], before.sp, after.sp),
+            _ => {
+                let writes: Vec<_> = self.destinations().iter().map(|reg| format!("r{} = {}", reg, after[reg])).collect();
+                if writes.is_empty() { "nothing".to_string() } else { writes.join(", ") }
+            },
+        };
+        match (before.zero, after.zero) {
+            (false, true) => format!("{}; zero flag set", explanation),
+            (true, false) => format!("{}; zero flag cleared", explanation),
+            _ => explanation,
+        }
+    }
+
+    /// Describes when a jump instruction is taken, or `None` for instructions that are not jumps.
+    pub fn condition(&self) -> Option<String> {
+        match self {
+            Instruction::Jz { .. } | Instruction::Callz { .. } | Instruction::Skipz => Some(Predicate::Zero.description().to_string()),
+            Instruction::Jnz { .. } | Instruction::Callnz { .. } | Instruction::Skipnz => Some(Predicate::NotZero.description().to_string()),
+            Instruction::Jc { .. } => Some("carry flag set".to_string()),
+            Instruction::Jg { .. } => Some("greater".to_string()),
+            Instruction::Jl { .. } => Some("less".to_string()),
+            Instruction::Jge { .. } => Some("greater or equal".to_string()),
+            Instruction::Jle { .. } => Some("less or equal".to_string()),
+            Instruction::Jnc { .. } => Some("carry flag clear".to_string()),
+            Instruction::J { .. } | Instruction::Call { .. } | Instruction::Ret | Instruction::Jr { .. } | Instruction::Halt { .. } => Some("always".to_string()),
+            Instruction::Jempty { .. } => Some("stack empty".to_string()),
+            Instruction::Jnempty { .. } => Some("stack not empty".to_string()),
+            Instruction::Halteq { a, b } => Some(format!("r{} == r{}", a, b)),
+            Instruction::Jsign { reg, .. } => Some(format!("r{} negative, zero or positive", reg)),
+            Instruction::Loopn { counter, limit, .. } => Some(format!("r{} < r{}", counter, limit)),
+            Instruction::Predicated { predicate, inner } => inner.condition().map(|condition| match condition.as_str() {
+                "always" => predicate.description().to_string(),
+                _ => format!("{} and {}", predicate.description(), condition),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The jump targets of the instruction, including the label read by `lea`, empty for instructions that are not
+    /// jumps.
+    pub fn labels(&self) -> Vec<&Label> {
+        match self {
+            Instruction::Jz { label } | Instruction::Jnz { label } | Instruction::Jc { label } | Instruction::Jnc { label }
+            | Instruction::Jg { label } | Instruction::Jl { label } | Instruction::Jge { label } | Instruction::Jle { label }
+            | Instruction::J { label } | Instruction::Loopn { label, .. }
+            | Instruction::Call { label } | Instruction::Callz { label } | Instruction::Callnz { label }
+            | Instruction::Jempty { label } | Instruction::Jnempty { label } | Instruction::Lea { label, .. } => vec![label],
+            Instruction::Jsign { neg, zero, pos, .. } => vec![neg, zero, pos],
+            Instruction::Predicated { inner, .. } => inner.labels(),
+            _ => vec![],
+        }
+    }
+
+    fn labels_mut(&mut self) -> Vec<&mut Label> {
+        match self {
+            Instruction::Jz { label } | Instruction::Jnz { label } | Instruction::Jc { label } | Instruction::Jnc { label }
+            | Instruction::Jg { label } | Instruction::Jl { label } | Instruction::Jge { label } | Instruction::Jle { label }
+            | Instruction::J { label } | Instruction::Loopn { label, .. }
+            | Instruction::Call { label } | Instruction::Callz { label } | Instruction::Callnz { label }
+            | Instruction::Jempty { label } | Instruction::Jnempty { label } | Instruction::Lea { label, .. } => vec![label],
+            Instruction::Jsign { neg, zero, pos, .. } => vec![neg, zero, pos],
+            Instruction::Predicated { inner, .. } => inner.labels_mut(),
+            _ => vec![],
+        }
+    }
+
+    /// The indices execution can continue at after the instruction at `index`. `ret` and `jr` have none since the
+    /// instruction after every call is already a successor of the call, and the labels read by `lea` are successors
+    /// of the `lea`.
+    pub fn successors(&self, state: &State, index: usize) -> Vec<usize> {
+        let mut successors: Vec<_> = self.labels().into_iter().filter_map(|label| state.resolve_label(label, index).ok()).collect();
+        match self {
+            Instruction::J { .. } | Instruction::Jsign { .. } | Instruction::Ret | Instruction::Jr { .. } | Instruction::Halt { .. } => (),
+            Instruction::Skipz | Instruction::Skipnz => successors.extend([index + 1, index + 2]),
+            _ => successors.push(index + 1),
+        }
+        successors
+    }
+
+    /// Returns a copy of the instruction with its jump targets replaced by the numeric indices they resolve to.
+    pub fn resolved(&self, state: &State, index: usize) -> Instruction {
+        let mut resolved = self.clone();
+        let lea = matches!(self, Instruction::Lea { .. });
+        for label in resolved.labels_mut() {
+            let target = match lea {
+                true => state.label_value(label, index),
+                false => state.resolve_label(label, index).map(|target| target as u64),
+            };
+            if let Ok(target) = target {
+                *label = target.to_string();
+            }
+        }
+        resolved
+    }
+
+    /// Returns a parser for `(index, line)` pairs which records any labels defined on the line in `state`.
+    pub fn parse<'a>(state: &'a mut State) -> impl FnMut((usize, &str)) -> Result<Instruction, InterpretError> + 'a {
+        |(index, src)| {
+            if src.len() > state.config.max_line_len {
+                return Err(InterpretError::parse(index, format!("line {} is longer than {} bytes", index + 1, state.config.max_line_len)));
+            }
+            let names = RegisterNames { count: state.registers.len(), aliases: state.aliases.clone() };
+            let mut code = strip_comment(src, state.config.strict_comments).to_string();
+            let mut line_labels = Vec::new();
+            let mut predicate = None;
+            loop {
+                let mut split = code.trim().splitn(2, char::is_whitespace);
+                let first = split.next().filter(|first| !first.is_empty());
+                let operands = tokenize_operands(split.next().unwrap_or(""));
+                // Labels are checked together with the rest of the line, and strings may contain empty pieces.
+                if first.is_some_and(|first| !takes_string(first) && !first.contains(':')) && operands.contains(&"") {
+                    return Err(InterpretError::parse(index, format!("empty operand on line {}", index + 1)));
+                }
+                let mut operands = operands.into_iter();
+                let val = match first {
+                    // Only the mnemonic is case insensitive, labels keep their case.
+                    Some(first) => match first.trim().to_lowercase().as_str() {
+                        "zero" => Instruction::Zero { reg: read_reg(&mut operands, index, &names)? },
+                        "debug" => Instruction::Debug { tag: operands.next().map(|tag| tag.trim().to_lowercase()) },
+                        "mov" => Instruction::Mov { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "add" => Instruction::Add { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_operand(&mut operands, index, &names)? },
+                        "sub" => Instruction::Sub { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_operand(&mut operands, index, &names)? },
+                        "inc" => Instruction::Inc { reg: read_reg(&mut operands, index, &names)? },
+                        "dec" => Instruction::Dec { reg: read_reg(&mut operands, index, &names)? },
+                        "and" => Instruction::And { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_operand(&mut operands, index, &names)? },
+                        "or" => Instruction::Or { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_operand(&mut operands, index, &names)? },
+                        "xor" => Instruction::Xor { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_operand(&mut operands, index, &names)? },
+                        "not" => Instruction::Not { reg: read_reg(&mut operands, index, &names)? },
+                        "shl" => Instruction::Shl { reg: read_reg(&mut operands, index, &names)?, amount: read_operand(&mut operands, index, &names)? },
+                        "shr" => Instruction::Shr { reg: read_reg(&mut operands, index, &names)?, amount: read_operand(&mut operands, index, &names)? },
+                        "jz" => Instruction::Jz { label: read_label(&mut operands, index)? },
+                        "jnz" => Instruction::Jnz { label: read_label(&mut operands, index)? },
+                        "jc" => Instruction::Jc { label: read_label(&mut operands, index)? },
+                        "jnc" => Instruction::Jnc { label: read_label(&mut operands, index)? },
+                        "jg" => Instruction::Jg { label: read_label(&mut operands, index)? },
+                        "jl" => Instruction::Jl { label: read_label(&mut operands, index)? },
+                        "jge" => Instruction::Jge { label: read_label(&mut operands, index)? },
+                        "jle" => Instruction::Jle { label: read_label(&mut operands, index)? },
+                        "cmp" => Instruction::Cmp { op1: read_reg(&mut operands, index, &names)?, op2: read_reg(&mut operands, index, &names)? },
+                        "j" => Instruction::J { label: read_label(&mut operands, index)? },
+                        "steps" => Instruction::Steps { reg: read_reg(&mut operands, index, &names)? },
+                        "paddb" => Instruction::Paddb { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_reg(&mut operands, index, &names)? },
+                        "pcmpeqb" => Instruction::Pcmpeqb { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_reg(&mut operands, index, &names)? },
+                        "sxtb" => Instruction::Sxtb { reg: read_reg(&mut operands, index, &names)? },
+                        "sxtw" => Instruction::Sxtw { reg: read_reg(&mut operands, index, &names)? },
+                        "sxtl" => Instruction::Sxtl { reg: read_reg(&mut operands, index, &names)? },
+                        "zxtb" => Instruction::Zxtb { reg: read_reg(&mut operands, index, &names)? },
+                        "zxtw" => Instruction::Zxtw { reg: read_reg(&mut operands, index, &names)? },
+                        "zxtl" => Instruction::Zxtl { reg: read_reg(&mut operands, index, &names)? },
+                        "minmax" => Instruction::Minmax { a: read_reg(&mut operands, index, &names)?, b: read_reg(&mut operands, index, &names)? },
+                        "loopn" => Instruction::Loopn { counter: read_reg(&mut operands, index, &names)?, limit: read_reg(&mut operands, index, &names)?, label: read_label(&mut operands, index)? },
+                        "rolr" => Instruction::Rolr { reg: read_reg(&mut operands, index, &names)?, by: read_reg(&mut operands, index, &names)? },
+                        "rorr" => Instruction::Rorr { reg: read_reg(&mut operands, index, &names)?, by: read_reg(&mut operands, index, &names)? },
+                        "split" => Instruction::Split { hi: read_reg(&mut operands, index, &names)?, lo: read_reg(&mut operands, index, &names)?, src: read_reg(&mut operands, index, &names)? },
+                        "join" => Instruction::Join { to: read_reg(&mut operands, index, &names)?, hi: read_reg(&mut operands, index, &names)?, lo: read_reg(&mut operands, index, &names)? },
+                        "load" => Instruction::Load { to: read_reg(&mut operands, index, &names)?, addr: read_reg(&mut operands, index, &names)? },
+                        "store" => Instruction::Store { addr: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "clamp" => Instruction::Clamp { reg: read_reg(&mut operands, index, &names)?, lo: read_reg(&mut operands, index, &names)?, hi: read_reg(&mut operands, index, &names)? },
+                        ".byte" => {
+                            let values = operands.by_ref()
+                                .map(|operand| match read_imm(&mut std::iter::once(operand), index)? {
+                                    value @ 0..=0xFF => Ok(value),
+                                    value => Err(InterpretError::parse_at(index, format!("{} does not fit in a byte on line {}", value, index + 1), operand)),
+                                })
+                                .collect::<Result<Vec<_>, _>>()?;
+                            state.place_data(&line_labels, &values, index)?;
+                            Instruction::Noop
+                        },
+                        ".ascii" => {
+                            let text = read_string(src, index)?;
+                            state.place_data(&line_labels, &text.bytes().map(u64::from).collect::<Vec<_>>(), index)?;
+                            return Ok(Instruction::Noop);
+                        },
+                        ".input" | ".output" => {
+                            let registers = operands.by_ref()
+                                .map(|operand| read_reg(&mut std::iter::once(operand), index, &names))
+                                .collect::<Result<Vec<_>, _>>()?;
+                            match first.trim().eq_ignore_ascii_case(".input") {
+                                true => state.inputs.extend(registers),
+                                false => state.outputs.extend(registers),
+                            }
+                            Instruction::Noop
+                        },
+                        ".alias" => {
+                            // The comma between the name and the register is optional, as in `.alias counter r3`.
+                            let name = read_label(&mut operands, index)?;
+                            let (name, reg) = match name.split_once(char::is_whitespace) {
+                                Some((name, reg)) => (name, read_reg(&mut std::iter::once(reg), index, &names)?),
+                                None => (name.as_str(), read_reg(&mut operands, index, &names)?),
+                            };
+                            state.add_alias(name, reg, index)?;
+                            Instruction::Noop
+                        },
+                        ".stack" => {
+                            state.reserve_stack(read_imm(&mut operands, index)?, index)?;
+                            Instruction::Noop
+                        },
+                        "incm" => Instruction::Incm { addr: read_reg(&mut operands, index, &names)? },
+                        "decm" => Instruction::Decm { addr: read_reg(&mut operands, index, &names)? },
+                        "abs" => Instruction::Abs { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "checksum" => Instruction::Checksum { reg: read_reg(&mut operands, index, &names)? },
+                        "proglen" => Instruction::Proglen { reg: read_reg(&mut operands, index, &names)? },
+                        "sumregs" => Instruction::Sumregs { reg: read_reg(&mut operands, index, &names)? },
+                        "xorregs" => Instruction::Xorregs { reg: read_reg(&mut operands, index, &names)? },
+                        "push" => Instruction::Push { from: read_reg(&mut operands, index, &names)? },
+                        "pop" => Instruction::Pop { to: read_reg(&mut operands, index, &names)? },
+                        "call" => Instruction::Call { label: read_label(&mut operands, index)? },
+                        "ret" => Instruction::Ret,
+                        "skipz" => Instruction::Skipz,
+                        "skipnz" => Instruction::Skipnz,
+                        "callz" => Instruction::Callz { label: read_label(&mut operands, index)? },
+                        "callnz" => Instruction::Callnz { label: read_label(&mut operands, index)? },
+                        "rothalf" => Instruction::Rothalf { reg: read_reg(&mut operands, index, &names)? },
+                        "time" => Instruction::Time { reg: read_reg(&mut operands, index, &names)? },
+                        "jempty" => Instruction::Jempty { label: read_label(&mut operands, index)? },
+                        "jnempty" => Instruction::Jnempty { label: read_label(&mut operands, index)? },
+                        "countif" => Instruction::Countif { acc: read_reg(&mut operands, index, &names)?, a: read_reg(&mut operands, index, &names)?, b: read_reg(&mut operands, index, &names)? },
+                        "modexp" => Instruction::Modexp { to: read_reg(&mut operands, index, &names)?, base: read_reg(&mut operands, index, &names)?, exp: read_reg(&mut operands, index, &names)?, modulus: read_reg(&mut operands, index, &names)? },
+                        "movi" => {
+                            let to = read_reg(&mut operands, index, &names)?;
+                            // A name instead of a value, as in `movi r0, msg`, loads what it refers to: the register of an
+                            // alias like `mov`, or the address or line of a label like `lea`.
+                            match operands.next() {
+                                Some(alias) if names.aliases.contains_key(alias.trim()) => Instruction::Mov { to, from: names.aliases[alias.trim()] },
+                                Some(label) if label.trim().starts_with(|c: char| c.is_alphabetic() || c == '_') => Instruction::Lea { reg: to, label: label.trim().to_string() },
+                                label => Instruction::Movi { to, value: read_imm(&mut label.into_iter(), index)? },
+                            }
+                        },
+                        "mulsat" => Instruction::Mulsat { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_reg(&mut operands, index, &names)? },
+                        "mul" => {
+                            let regs = [read_reg(&mut operands, index, &names)?, read_reg(&mut operands, index, &names)?, read_reg(&mut operands, index, &names)?];
+                            match operands.next() {
+                                Some(op2) => Instruction::Mul { hi: Some(regs[0]), lo: regs[1], op1: regs[2], op2: read_reg(&mut std::iter::once(op2), index, &names)? },
+                                None => Instruction::Mul { hi: None, lo: regs[0], op1: regs[1], op2: regs[2] },
+                            }
+                        },
+                        "div" => Instruction::Div { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_reg(&mut operands, index, &names)? },
+                        "mod" => Instruction::Mod { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_reg(&mut operands, index, &names)? },
+                        "idiv" => Instruction::Idiv { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_reg(&mut operands, index, &names)? },
+                        "imod" => Instruction::Imod { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_reg(&mut operands, index, &names)? },
+                        "rbit" => Instruction::Rbit { reg: read_reg(&mut operands, index, &names)? },
+                        "seteqi" => Instruction::Seteqi { to: read_reg(&mut operands, index, &names)?, src: read_reg(&mut operands, index, &names)?, imm: read_imm(&mut operands, index)? },
+                        "setlti" => Instruction::Setlti { to: read_reg(&mut operands, index, &names)?, src: read_reg(&mut operands, index, &names)?, imm: read_imm(&mut operands, index)? },
+                        "setgti" => Instruction::Setgti { to: read_reg(&mut operands, index, &names)?, src: read_reg(&mut operands, index, &names)?, imm: read_imm(&mut operands, index)? },
+                        "neg" => Instruction::Neg { reg: read_reg(&mut operands, index, &names)? },
+                        "paddw" => Instruction::Paddw { to: read_reg(&mut operands, index, &names)?, op1: read_reg(&mut operands, index, &names)?, op2: read_reg(&mut operands, index, &names)? },
+                        "rol" => Instruction::Rol { reg: read_reg(&mut operands, index, &names)?, amount: read_operand(&mut operands, index, &names)? },
+                        "ror" => Instruction::Ror { reg: read_reg(&mut operands, index, &names)?, amount: read_operand(&mut operands, index, &names)? },
+                        "parityof" => Instruction::Parityof { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "out" => Instruction::Out { reg: read_reg(&mut operands, index, &names)? },
+                        "putc" => Instruction::Putc { reg: read_reg(&mut operands, index, &names)? },
+                        "loadd" => Instruction::Loadd { to: read_reg(&mut operands, index, &names)?, base: read_reg(&mut operands, index, &names)?, disp: read_imm(&mut operands, index)? },
+                        "stored" => Instruction::Stored { from: read_reg(&mut operands, index, &names)?, base: read_reg(&mut operands, index, &names)?, disp: read_imm(&mut operands, index)? },
+                        "in" => Instruction::In { reg: read_reg(&mut operands, index, &names)? },
+                        "memset" => Instruction::Memset { addr: read_reg(&mut operands, index, &names)?, val: read_reg(&mut operands, index, &names)?, len: read_reg(&mut operands, index, &names)? },
+                        "cmovc" => Instruction::Cmovc { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "cmovnc" => Instruction::Cmovnc { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "isqrt" => Instruction::Isqrt { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "popcntall" => Instruction::Popcntall { reg: read_reg(&mut operands, index, &names)? },
+                        "jsign" => Instruction::Jsign { reg: read_reg(&mut operands, index, &names)?, neg: read_label(&mut operands, index)?, zero: read_label(&mut operands, index)?, pos: read_label(&mut operands, index)? },
+                        "cas" => Instruction::Cas { addr: read_reg(&mut operands, index, &names)?, expected: read_reg(&mut operands, index, &names)?, new: read_reg(&mut operands, index, &names)? },
+                        "snapshot" => {
+                            // The tag is read from the whole line since it may contain commas and comment markers.
+                            let tag = read_string(src, index)?;
+                            operands.by_ref().for_each(drop);
+                            Instruction::Snapshot { tag }
+                        },
+                        "halteq" => Instruction::Halteq { a: read_reg(&mut operands, index, &names)?, b: read_reg(&mut operands, index, &names)? },
+                        "jr" => Instruction::Jr { reg: read_reg(&mut operands, index, &names)? },
+                        "lea" => Instruction::Lea { reg: read_reg(&mut operands, index, &names)?, label: read_label(&mut operands, index)? },
+                        "lut" => {
+                            let to = read_reg(&mut operands, index, &names)?;
+                            let idx = read_reg(&mut operands, index, &names)?;
+                            let values = operands.by_ref().map(|operand| read_imm(&mut std::iter::once(operand), index)).collect::<Result<Vec<_>, _>>()?;
+                            if values.is_empty() {
+                                return Err(InterpretError::parse(index, format!("garbage following instruction on line {}", index + 1)));
+                            }
+                            Instruction::Lut { to, idx, values }
+                        },
+                        "cmovz" => Instruction::Cmovz { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "cmovnz" => Instruction::Cmovnz { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "test" => Instruction::Test { op1: read_reg(&mut operands, index, &names)?, op2: read_reg(&mut operands, index, &names)? },
+                        "normalize" => Instruction::Normalize { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "readline" => Instruction::Readline { addr: read_reg(&mut operands, index, &names)?, len: read_reg(&mut operands, index, &names)? },
+                        "halt" => Instruction::Halt { code: operands.next().map(|reg| read_reg(&mut std::iter::once(reg), index, &names)).transpose()? },
+                        "bsr" => Instruction::Bsr { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        "bsf" => Instruction::Bsf { to: read_reg(&mut operands, index, &names)?, from: read_reg(&mut operands, index, &names)? },
+                        _ => {
+                            let (label, after) = first.trim().split_once(":").ok_or_else(|| InterpretError::GarbageInstruction { line: index + 1, text: first.trim().to_string() })?;
+                            if let Some(condition) = Predicate::from_name(&label.to_lowercase()) {
+                                if predicate.replace(condition).is_some() {
+                                    return Err(InterpretError::parse(index, format!("More than one predicate on line {}", index + 1)));
+                                }
+                            } else if predicate.is_some() {
+                                return Err(InterpretError::parse(index, format!("Label `{}` after the predicate on line {}", label, index + 1)));
+                            } else {
+                                state.add_label(label.to_string(), index)?;
+                                line_labels.push(label.to_string());
+                            }
+                            // The instruction may follow the colon without a space, as in `loop:inc r0`.
+                            let rest = operands.collect::<Vec<_>>().join(",");
+                            code = format!("{} {}", after, rest);
+                            continue;
+                        },
+                    },
+                    None => Instruction::Noop,
+                };
+                if let Some(extra) = operands.next() {
+                    return Err(InterpretError::parse_at(index, format!("Too many operands on line {}", index + 1), extra));
+                }
+                return match (predicate, val) {
+                    (Some(_), Instruction::Noop) => Err(InterpretError::parse(index, format!("Predicate without an instruction on line {}", index + 1))),
+                    (Some(predicate), val) => Ok(Instruction::Predicated { predicate, inner: Box::new(val) }),
+                    (None, val) => Ok(val),
+                };
+            }
+        }
+    }
+}
+
+impl Instruction {
+    /// The mnemonic and the operands of the instruction as they are printed, with immediates in hex if `hex` is set.
+    /// `Noop` and predicated instructions have none.
+    fn parts(&self, hex: bool) -> Option<(&'static str, Vec<String>)> {
+        let r = |reg: &Register| format!("r{}", reg);
+        let imm = |value: &u64| if hex { format!("0x{:X}", value) } else { value.to_string() };
+        let operand = |operand: &Operand| match operand {
+            Operand::Reg(reg) => r(reg),
+            Operand::Imm(value) => imm(&value.0),
+        };
+        Some(match self {
+            Instruction::Noop | Instruction::Predicated { .. } => return None,
+            Instruction::Debug { tag } => ("debug", tag.iter().cloned().collect()),
+            Instruction::Zero { reg } => ("zero", vec![r(reg)]),
+            Instruction::Mov { to, from } => ("mov", vec![r(to), r(from)]),
+            Instruction::Add { to, op1, op2 } => ("add", vec![r(to), r(op1), operand(op2)]),
+            Instruction::Sub { to, op1, op2 } => ("sub", vec![r(to), r(op1), operand(op2)]),
+            Instruction::Inc { reg } => ("inc", vec![r(reg)]),
+            Instruction::Dec { reg } => ("dec", vec![r(reg)]),
+            Instruction::And { to, op1, op2 } => ("and", vec![r(to), r(op1), operand(op2)]),
+            Instruction::Or { to, op1, op2 } => ("or", vec![r(to), r(op1), operand(op2)]),
+            Instruction::Xor { to, op1, op2 } => ("xor", vec![r(to), r(op1), operand(op2)]),
+            Instruction::Not { reg } => ("not", vec![r(reg)]),
+            Instruction::Shl { reg, amount } => ("shl", vec![r(reg), operand(amount)]),
+            Instruction::Shr { reg, amount } => ("shr", vec![r(reg), operand(amount)]),
+            Instruction::Jz { label } => ("jz", vec![label.clone()]),
+            Instruction::Jnz { label } => ("jnz", vec![label.clone()]),
+            Instruction::Jc { label } => ("jc", vec![label.clone()]),
+            Instruction::Jnc { label } => ("jnc", vec![label.clone()]),
+            Instruction::Jg { label } => ("jg", vec![label.clone()]),
+            Instruction::Jl { label } => ("jl", vec![label.clone()]),
+            Instruction::Jge { label } => ("jge", vec![label.clone()]),
+            Instruction::Jle { label } => ("jle", vec![label.clone()]),
+            Instruction::Cmp { op1, op2 } => ("cmp", vec![r(op1), r(op2)]),
+            Instruction::J { label } => ("j", vec![label.clone()]),
+            Instruction::Steps { reg } => ("steps", vec![r(reg)]),
+            Instruction::Loopn { counter, limit, label } => ("loopn", vec![r(counter), r(limit), label.clone()]),
+            Instruction::Paddb { to, op1, op2 } => ("paddb", vec![r(to), r(op1), r(op2)]),
+            Instruction::Pcmpeqb { to, op1, op2 } => ("pcmpeqb", vec![r(to), r(op1), r(op2)]),
+            Instruction::Sxtb { reg } => ("sxtb", vec![r(reg)]),
+            Instruction::Sxtw { reg } => ("sxtw", vec![r(reg)]),
+            Instruction::Sxtl { reg } => ("sxtl", vec![r(reg)]),
+            Instruction::Zxtb { reg } => ("zxtb", vec![r(reg)]),
+            Instruction::Zxtw { reg } => ("zxtw", vec![r(reg)]),
+            Instruction::Zxtl { reg } => ("zxtl", vec![r(reg)]),
+            Instruction::Minmax { a, b } => ("minmax", vec![r(a), r(b)]),
+            Instruction::Rolr { reg, by } => ("rolr", vec![r(reg), r(by)]),
+            Instruction::Rorr { reg, by } => ("rorr", vec![r(reg), r(by)]),
+            Instruction::Split { hi, lo, src } => ("split", vec![r(hi), r(lo), r(src)]),
+            Instruction::Join { to, hi, lo } => ("join", vec![r(to), r(hi), r(lo)]),
+            Instruction::Load { to, addr } => ("load", vec![r(to), r(addr)]),
+            Instruction::Store { addr, from } => ("store", vec![r(addr), r(from)]),
+            Instruction::Clamp { reg, lo, hi } => ("clamp", vec![r(reg), r(lo), r(hi)]),
+            Instruction::Incm { addr } => ("incm", vec![r(addr)]),
+            Instruction::Decm { addr } => ("decm", vec![r(addr)]),
+            Instruction::Abs { to, from } => ("abs", vec![r(to), r(from)]),
+            Instruction::Checksum { reg } => ("checksum", vec![r(reg)]),
+            Instruction::Proglen { reg } => ("proglen", vec![r(reg)]),
+            Instruction::Sumregs { reg } => ("sumregs", vec![r(reg)]),
+            Instruction::Xorregs { reg } => ("xorregs", vec![r(reg)]),
+            Instruction::Push { from } => ("push", vec![r(from)]),
+            Instruction::Pop { to } => ("pop", vec![r(to)]),
+            Instruction::Call { label } => ("call", vec![label.clone()]),
+            Instruction::Ret => ("ret", vec![]),
+            Instruction::Skipz => ("skipz", vec![]),
+            Instruction::Skipnz => ("skipnz", vec![]),
+            Instruction::Callz { label } => ("callz", vec![label.clone()]),
+            Instruction::Callnz { label } => ("callnz", vec![label.clone()]),
+            Instruction::Rothalf { reg } => ("rothalf", vec![r(reg)]),
+            Instruction::Time { reg } => ("time", vec![r(reg)]),
+            Instruction::Jempty { label } => ("jempty", vec![label.clone()]),
+            Instruction::Jnempty { label } => ("jnempty", vec![label.clone()]),
+            Instruction::Countif { acc, a, b } => ("countif", vec![r(acc), r(a), r(b)]),
+            Instruction::Modexp { to, base, exp, modulus } => ("modexp", vec![r(to), r(base), r(exp), r(modulus)]),
+            Instruction::Movi { to, value } => ("movi", vec![r(to), imm(value)]),
+            Instruction::Mulsat { to, op1, op2 } => ("mulsat", vec![r(to), r(op1), r(op2)]),
+            Instruction::Mul { hi, lo, op1, op2 } => ("mul", hi.iter().chain([lo, op1, op2]).map(r).collect()),
+            Instruction::Div { to, op1, op2 } => ("div", vec![r(to), r(op1), r(op2)]),
+            Instruction::Mod { to, op1, op2 } => ("mod", vec![r(to), r(op1), r(op2)]),
+            Instruction::Idiv { to, op1, op2 } => ("idiv", vec![r(to), r(op1), r(op2)]),
+            Instruction::Imod { to, op1, op2 } => ("imod", vec![r(to), r(op1), r(op2)]),
+            Instruction::Rbit { reg } => ("rbit", vec![r(reg)]),
+            Instruction::Seteqi { to, src, imm: value } => ("seteqi", vec![r(to), r(src), imm(value)]),
+            Instruction::Setlti { to, src, imm: value } => ("setlti", vec![r(to), r(src), imm(value)]),
+            Instruction::Setgti { to, src, imm: value } => ("setgti", vec![r(to), r(src), imm(value)]),
+            Instruction::Neg { reg } => ("neg", vec![r(reg)]),
+            Instruction::Paddw { to, op1, op2 } => ("paddw", vec![r(to), r(op1), r(op2)]),
+            Instruction::Rol { reg, amount } => ("rol", vec![r(reg), operand(amount)]),
+            Instruction::Ror { reg, amount } => ("ror", vec![r(reg), operand(amount)]),
+            Instruction::Parityof { to, from } => ("parityof", vec![r(to), r(from)]),
+            Instruction::Out { reg } => ("out", vec![r(reg)]),
+            Instruction::Putc { reg } => ("putc", vec![r(reg)]),
+            Instruction::Loadd { to, base, disp } => ("loadd", vec![r(to), r(base), imm(disp)]),
+            Instruction::Stored { from, base, disp } => ("stored", vec![r(from), r(base), imm(disp)]),
+            Instruction::In { reg } => ("in", vec![r(reg)]),
+            Instruction::Memset { addr, val, len } => ("memset", vec![r(addr), r(val), r(len)]),
+            Instruction::Cmovc { to, from } => ("cmovc", vec![r(to), r(from)]),
+            Instruction::Cmovnc { to, from } => ("cmovnc", vec![r(to), r(from)]),
+            Instruction::Isqrt { to, from } => ("isqrt", vec![r(to), r(from)]),
+            Instruction::Popcntall { reg } => ("popcntall", vec![r(reg)]),
+            Instruction::Jsign { reg, neg, zero, pos } => ("jsign", vec![r(reg), neg.clone(), zero.clone(), pos.clone()]),
+            Instruction::Cas { addr, expected, new } => ("cas", vec![r(addr), r(expected), r(new)]),
+            Instruction::Snapshot { tag } => ("snapshot", vec![format!("{:?}", tag)]),
+            Instruction::Halteq { a, b } => ("halteq", vec![r(a), r(b)]),
+            Instruction::Jr { reg } => ("jr", vec![r(reg)]),
+            Instruction::Lea { reg, label } => ("lea", vec![r(reg), label.clone()]),
+            Instruction::Lut { to, idx, values } => ("lut", [r(to), r(idx)].into_iter().chain(values.iter().map(imm)).collect()),
+            Instruction::Cmovz { to, from } => ("cmovz", vec![r(to), r(from)]),
+            Instruction::Cmovnz { to, from } => ("cmovnz", vec![r(to), r(from)]),
+            Instruction::Test { op1, op2 } => ("test", vec![r(op1), r(op2)]),
+            Instruction::Normalize { to, from } => ("normalize", vec![r(to), r(from)]),
+            Instruction::Readline { addr, len } => ("readline", vec![r(addr), r(len)]),
+            Instruction::Halt { code } => ("halt", code.iter().map(r).collect()),
+            Instruction::Bsr { to, from } => ("bsr", vec![r(to), r(from)]),
+            Instruction::Bsf { to, from } => ("bsf", vec![r(to), r(from)]),
+        })
+    }
+
+    /// The mnemonic the instruction is written with, that of the inner instruction for predicated instructions and
+    /// `None` for lines without an instruction.
+    pub fn mnemonic(&self) -> Option<&'static str> {
+        match self {
+            Instruction::Predicated { inner, .. } => inner.mnemonic(),
+            _ => self.parts(false).map(|(mnemonic, _)| mnemonic),
+        }
+    }
+}
+
+/// Instructions are printed in a canonical form: the mnemonic left-aligned in a 7 character column followed by
+/// a single space and the operands separated by `, `. Instructions without operands are printed as the bare
+/// mnemonic and `Noop` as an empty string. Immediates are decimal, or `0x` prefixed hex with the alternate
+/// flag (`{:#}`).
+impl Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::Predicated { predicate, inner } if f.alternate() => write!(f, "{}: {:#}", predicate, inner),
+            Instruction::Predicated { predicate, inner } => write!(f, "{}: {}", predicate, inner),
+            _ => match self.parts(f.alternate()) {
+                None => Ok(()),
+                Some((mnemonic, operands)) if operands.is_empty() => write!(f, "{}", mnemonic),
+                Some((mnemonic, operands)) => write!(f, "{:<7} {}", mnemonic, operands.join(", ")),
+            },
+        }
+    }
+}
+
+/// 64 bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: impl Iterator<Item = u8>) -> u64 {
+    bytes.fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// `base` to the power of `exp` modulo `modulus`, by square and multiply. `modulus` must not be zero.
+fn modpow(base: u64, mut exp: u64, modulus: u64) -> u64 {
+    let modulus = modulus as u128;
+    let mut base = base as u128 % modulus;
+    let mut result = 1 % modulus;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result * base % modulus;
+        }
+        base = base * base % modulus;
+        exp >>= 1;
+    }
+    result as u64
+}
+
+/// Applies `op` to each pair of `width` bit lanes of `a` and `b`, truncating each result to its lane.
+fn lanewise(a: u64, b: u64, width: u32, op: impl Fn(u64, u64) -> u64) -> u64 {
+    let mask = u64::MAX >> (64 - width);
+    (0..64).step_by(width as usize).fold(0, |acc, shift| acc | (op((a >> shift) & mask, (b >> shift) & mask) & mask) << shift)
+}
+
+/// Numeric jump targets are instruction indices and may point one past the last instruction to end the program.
+fn validate_targets(instructions: &[Instruction], state: &State) -> Result<(), InterpretError> {
+    let errors = instructions.iter()
+        .enumerate()
+        .flat_map(|(index, instruction)| instruction.labels().into_iter().map(move |label| (index, instruction, label)))
+        // Only `lea` can refer to data.
+        .filter(|(_, instruction, label)| !(matches!(instruction, Instruction::Lea { .. }) && state.data_address(label).is_some_and(|addr| addr >= 0)))
+        .filter_map(|(index, _, label)| {
+            match state.target(label) {
+                Some(target) if target < 0 || target > instructions.len() as i64 => Some(InterpretError::JumpOutOfRange { line: index + 1, target }),
+                Some(_) => None,
+                None => Some(InterpretError::UnknownLabel { line: index + 1, name: label.clone() }),
+            }
+        })
+        .collect();
+    InterpretError::collect(errors)
+}
+
+/// Runs `instructions` until the program counter moves past the last instruction, reading any input the program
+/// needs from `input` and sending everything it prints to `output`. `hook` is called with the state, the program
+/// counter and the instruction about to be executed before every step. Stops at the first instruction that fails.
+pub fn run(
+    state: &mut State,
+    instructions: &[Instruction],
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    mut hook: impl FnMut(&State, usize, &Instruction),
+) -> Result<(), InterpretError> {
+    let mut pc = 0;
+    state.program_len = instructions.len();
+    state.executions.resize(instructions.len(), 0);
+    state.truncate_registers();
+    while pc != instructions.len() {
+        hook(state, pc, &instructions[pc]);
+        let index = pc;
+        // Only stop after the instruction if stepping was already on, not when it was turned on by its own prompt,
+        // and not after a `debug` that just stopped.
+        let stepping = state.single_step && !matches!(&instructions[index], Instruction::Debug { tag } if state.config.debug_enabled(tag.as_deref()));
+        pc = instructions[index].apply(state, index, input, output)?;
+        state.truncate_registers();
+        state.steps += 1;
+        state.executions[index] += 1;
+        if let Some((access, value)) = state.trapped.take() {
+            let addr = state.config.trap_addr.unwrap_or_default();
+            let message = format!("line {} {} {} at memory[{}]", index + 1, access, value, addr);
+            writeln!(output, "{} {}", ansi_term::Color::Yellow.paint("Trap:"), message).map_err(io_error(index))?;
+            state.write_dump(output).map_err(io_error(index))?;
+            if !state.config.sandbox {
+                pc = debug_prompt(state, pc, false, input, output)?.unwrap_or(pc);
+            }
+            state.trapped.set(None);
+        }
+        if stepping && state.single_step && pc != instructions.len() {
+            writeln!(output, "{} line {}: `{}`", ansi_term::Color::Yellow.paint("Step:"), index + 1, instructions[index]).map_err(io_error(index))?;
+            state.write_dump_compact(output).map_err(io_error(index))?;
+            pc = debug_prompt(state, pc, false, input, output)?.unwrap_or(pc);
+        }
+    }
+    Ok(())
+}
+
+/// Runs several programs, each with its own state, one instruction of each in turn until all of them have finished.
+/// The programs share the memory of the last state, which holds the data of all of them if each state took over the
+/// memory of the previous one with `State::continue_memory` before loading its program. Every state holds the
+/// shared memory when they are done, while registers, flags and stack pointers stay separate. `hook` is called like for `run`, along with the position of the program.
+/// An error stops all programs and is returned with the position of the program that failed.
+pub fn run_coop(
+    states: &mut [State],
+    programs: &[Vec<Instruction>],
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    mut hook: impl FnMut(usize, &State, usize, &Instruction),
+) -> Result<(), (usize, InterpretError)> {
+    let mut memory = states.last_mut().map(|state| std::mem::take(&mut state.memory)).unwrap_or_default();
+    let mut pcs = vec![0; programs.len()];
+    for (state, instructions) in states.iter_mut().zip(programs) {
+        state.program_len = instructions.len();
+        state.executions.resize(instructions.len(), 0);
+        state.truncate_registers();
+    }
+    let mut result = Ok(());
+    'run: while pcs.iter().zip(programs).any(|(&pc, instructions)| pc != instructions.len()) {
+        for (program, ((state, instructions), pc)) in states.iter_mut().zip(programs).zip(&mut pcs).enumerate() {
+            if *pc == instructions.len() {
+                continue;
+            }
+            hook(program, state, *pc, &instructions[*pc]);
+            // The memory is moved into the state of the program whose turn it is.
+            std::mem::swap(&mut state.memory, &mut memory);
+            let next = instructions[*pc].apply(state, *pc, input, output);
+            std::mem::swap(&mut state.memory, &mut memory);
+            match next {
+                Ok(next) => {
+                    state.executions[*pc] += 1;
+                    *pc = next;
+                },
+                Err(error) => {
+                    result = Err((program, error));
+                    break 'run;
+                },
+            }
+            state.truncate_registers();
+            state.steps += 1;
+        }
+    }
+    for state in states.iter_mut() {
+        state.memory = memory.clone();
+    }
+    result
+}
+
+/// Parses and runs `source` with the registers in `initial` set beforehand and returns the final state. The program
+/// reads no input and anything it prints is discarded, so `debug` does not stop.
+pub fn run_source(source: &str, initial: &[(Register, u64)]) -> Result<State, InterpretError> {
+    let mut state = State::new();
+    for &(reg, value) in initial {
+        if reg >= state.registers.len() {
+            return Err(InterpretError::UnknownInitialRegister { reg });
+        }
+        state[&reg] = Wrapping(value);
+    }
+    let instructions = state.load_program(source)?;
+    run(&mut state, &instructions, &mut io::empty(), &mut io::sink(), |_, _, _| ())?;
+    Ok(state)
+}
+
+/// Parses a whole program without running it, returning the instructions and the labels defined by it.
+pub fn parse_program(source: &str) -> Result<(Vec<Instruction>, HashMap<String, usize>), InterpretError> {
+    let mut state = State::new();
+    let instructions = state.load_program(source)?;
+    Ok((instructions, state.labels))
+}
+
+/// Splits the offset from a jump target like `loop+2` or `loop - 1`, which is 0 if there is none.
+fn split_offset(label: &str) -> (&str, i64) {
+    let offset = label.rfind(['+', '-'])
+        .filter(|&at| at > 0)
+        .and_then(|at| Some((label[..at].trim(), label[at..].replace(' ', "").parse::<i64>().ok()?)));
+    offset.unwrap_or((label, 0))
+}
+
+/// The part of a line of the program before any comment. With `strict` a comment marker only counts at the start
+/// of the line or after whitespace.
+fn strip_comment(src: &str, strict: bool) -> &str {
+    let boundary = |at: &usize| !strict || src[..*at].chars().next_back().is_none_or(char::is_whitespace);
+    // The earliest marker starts the comment, whichever kind it is.
+    let start = [
+        src.match_indices("//").map(|(at, _)| at).find(boundary),
+        src.match_indices(';').map(|(at, _)| at).find(boundary),
+        hash_comment(src, boundary),
+    ];
+    start.into_iter().flatten().min().map_or(src, |at| &src[..at])
+}
+
+/// Whether `line` of a program holds a directive such as `.byte 1, 2`, which does nothing when executed but changes
+/// how the program is loaded.
+pub fn is_directive(line: &str, config: &Config) -> bool {
+    split_labels(strip_comment(line, config.strict_comments)).1.starts_with('.')
+}
+
+/// Splits the labels defined at the start of `code` from the instruction following them, e.g. `loop: inc r0` into
+/// `["loop"]` and `inc r0`. Predicates are returned as labels.
+fn split_labels(code: &str) -> (Vec<&str>, &str) {
+    let mut labels = Vec::new();
+    let mut rest = code.trim_start();
+    while let Some((label, _)) = rest.split(char::is_whitespace).next().and_then(|first| first.split_once(':')) {
+        labels.push(label);
+        rest = rest[label.len() + 1..].trim_start();
+    }
+    (labels, rest)
+}
+
+/// Expands every `invoke name` to the body of the routine defined between `.inline name` and `.endinline`. The
+/// labels defined in a body get a suffix unique to the invocation, e.g. `loop@1`, so the copies do not clash. The
+/// lines of the definitions are left empty, so lines only move after the first `invoke`.
+pub fn expand_inline(source: &str, config: &Config) -> Result<String, InterpretError> {
+    let mut routines: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut defining: Option<(usize, &str, Vec<&str>)> = None;
+    let mut lines = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let mut words = strip_comment(line, config.strict_comments).split_whitespace();
+        let directive = words.next().map(str::to_lowercase);
+        match (directive.as_deref(), &mut defining) {
+            (Some(".inline"), Some((start, ..))) => {
+                return Err(InterpretError::parse(index, format!("`.inline` on line {} inside the routine started on line {}", index + 1, *start + 1)));
+            },
+            (Some(".inline"), None) => {
+                let name = words.next().ok_or_else(|| InterpretError::parse(index, format!("`.inline` expects the name of a routine on line {}", index + 1)))?;
+                if let Some(extra) = words.next() {
+                    return Err(InterpretError::parse_at(index, format!("Too many operands on line {}", index + 1), extra));
+                }
+                if routines.contains_key(name) {
+                    return Err(InterpretError::parse_at(index, format!("routine `{}` already defined, redefined on line {}", name, index + 1), name));
+                }
+                defining = Some((index, name, Vec::new()));
+            },
+            (Some(".endinline"), None) => return Err(InterpretError::parse(index, format!("`.endinline` without `.inline` on line {}", index + 1))),
+            (Some(".endinline"), Some(_)) => {
+                if let Some((_, name, body)) = defining.take() {
+                    routines.insert(name, body);
+                }
+            },
+            (_, Some((_, _, body))) => body.push(line),
+            (_, None) => {
+                lines.push(line);
+                continue;
+            },
+        }
+        lines.push("");
+    }
+    if let Some((start, name, _)) = defining {
+        return Err(InterpretError::parse(start, format!("routine `{}` on line {} is missing `.endinline`", name, start + 1)));
+    }
+    let mut expanded = Vec::new();
+    let mut invocations = 0;
+    for (index, line) in lines.into_iter().enumerate() {
+        splice(line, index, &routines, config.strict_comments, &mut Vec::new(), &mut invocations, &mut expanded)?;
+    }
+    // Every line gets its newline back, so a program ending in an empty line keeps it.
+    Ok(expanded.into_iter().map(|line| line + "\n").collect())
+}
+
+/// Appends `line` to `expanded`, or the body of the routine if it is an `invoke`. `active` holds the routines
+/// being expanded, to reject a routine that invokes itself, and `index` the line of the outermost `invoke`.
+fn splice(
+    line: &str,
+    index: usize,
+    routines: &HashMap<&str, Vec<&str>>,
+    strict_comments: bool,
+    active: &mut Vec<String>,
+    invocations: &mut usize,
+    expanded: &mut Vec<String>,
+) -> Result<(), InterpretError> {
+    let (labels, code) = split_labels(strip_comment(line, strict_comments));
+    let mut words = code.split_whitespace();
+    if !words.next().is_some_and(|first| first.eq_ignore_ascii_case("invoke")) {
+        expanded.push(line.to_string());
+        return Ok(());
+    }
+    let name = words.next().ok_or_else(|| InterpretError::parse(index, format!("`invoke` expects the name of a routine on line {}", index + 1)))?;
+    if let Some(extra) = words.next() {
+        return Err(InterpretError::parse_at(index, format!("Too many operands on line {}", index + 1), extra));
+    }
+    if labels.iter().any(|label| Predicate::from_name(&label.to_lowercase()).is_some()) {
+        return Err(InterpretError::parse(index, format!("`invoke` cannot have a predicate on line {}", index + 1)));
+    }
+    let body = routines.get(name).ok_or_else(|| InterpretError::parse_at(index, format!("unknown routine `{}` on line {}", name, index + 1), name))?;
+    if active.iter().any(|active| active == name) {
+        return Err(InterpretError::parse_at(index, format!("routine `{}` invokes itself on line {}", name, index + 1), name));
+    }
+    *invocations += 1;
+    let suffix = format!("@{}", invocations);
+    let local: Vec<&str> = body.iter()
+        .flat_map(|line| split_labels(strip_comment(line, strict_comments)).0)
+        .filter(|label| Predicate::from_name(&label.to_lowercase()).is_none())
+        .collect();
+    let mut renamed: Vec<String> = body.iter().map(|line| rename_labels(line, &local, &suffix, strict_comments)).collect();
+    // Labels before the `invoke` point to the first line of the body.
+    let prefix: String = labels.iter().map(|label| format!("{}: ", label)).collect();
+    match renamed.first_mut() {
+        Some(first) => first.insert_str(0, &prefix),
+        None => renamed.push(prefix),
+    }
+    active.push(name.to_string());
+    for line in renamed {
+        splice(&line, index, routines, strict_comments, active, invocations, expanded)?;
+    }
+    active.pop();
+    Ok(())
+}
+
+/// Whether the operand of `mnemonic` is a string, which may contain commas and comment markers.
+fn takes_string(mnemonic: &str) -> bool {
+    mnemonic.eq_ignore_ascii_case(".ascii") || mnemonic.eq_ignore_ascii_case("snapshot")
+}
+
+/// Appends `suffix` to the definitions of `labels` on `line` and to the operands jumping to them.
+fn rename_labels(line: &str, labels: &[&str], suffix: &str, strict_comments: bool) -> String {
+    let code = strip_comment(line, strict_comments);
+    let rename = |label: &str| match labels.contains(&label) {
+        true => format!("{}{}", label, suffix),
+        false => label.to_string(),
+    };
+    let (defined, rest) = split_labels(code);
+    let mut renamed: String = defined.iter().map(|label| format!("{}: ", rename(label))).collect();
+    let (mnemonic, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    renamed.push_str(mnemonic);
+    if takes_string(mnemonic) {
+        renamed.push(' ');
+        renamed.push_str(operands);
+    } else if !operands.trim().is_empty() {
+        let operands: Vec<_> = operands.split(',')
+            .map(|operand| {
+                let operand = operand.trim();
+                let at = operand.rfind(['+', '-']).filter(|&at| at > 0).unwrap_or(operand.len());
+                format!("{}{}", rename(operand[..at].trim()), &operand[at..])
+            })
+            .collect();
+        renamed.push(' ');
+        renamed.push_str(&operands.join(", "));
+    }
+    renamed + &line[code.len()..]
+}
+
+/// Finds the `#` starting a comment on `line` among those at a position accepted by `boundary`. A `#` directly
+/// followed by an immediate, such as `#5`, `#-5` or `#'A'`, marks that immediate instead.
+fn hash_comment(line: &str, boundary: impl Fn(&usize) -> bool) -> Option<usize> {
+    let mut from = 0;
+    while let Some(at) = line[from..].find('#').map(|at| from + at) {
+        let immediate = Some(immediate_token(&line[at + 1..])).filter(|&imm| parse_value(imm).is_some());
+        if immediate.is_none() && boundary(&at) {
+            return Some(at);
+        }
+        // The immediate is skipped so a `#` inside it, as in `#'#'`, isn't taken for a comment.
+        from = at + 1 + immediate.map_or(0, str::len);
+    }
+    None
+}
+
+/// The immediate that `text` may start with: a character in single quotes, or everything up to the first character
+/// that can't be part of a number.
+fn immediate_token(text: &str) -> &str {
+    let end = match text.strip_prefix('\'') {
+        Some(quoted) => {
+            let mut chars = quoted.chars();
+            let len = match chars.next() {
+                Some('\\') => 1 + chars.next().map_or(0, char::len_utf8),
+                c => c.map_or(0, char::len_utf8),
+            };
+            match quoted[len..].starts_with('\'') {
+                true => len + 2,
+                false => 0,
+            }
+        },
+        None => text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-')).unwrap_or(text.len()),
+    };
+    &text[..end]
+}
+
+/// Splits the operands following a mnemonic at the commas and trims them, keeping empty operands so they can be
+/// rejected. A blank string has no operands.
+fn tokenize_operands(operands: &str) -> Vec<&str> {
+    match operands.trim() {
+        "" => Vec::new(),
+        operands => operands.split(',').map(str::trim).collect(),
+    }
+}
+
+/// Reads the next operand with `read`, pointing the error at the operand if it is malformed.
+fn read_next<'a, T>(
+    operands: &mut impl Iterator<Item = &'a str>,
+    index: usize,
+    read: impl FnOnce(&mut std::iter::Once<&'a str>) -> Option<T>,
+) -> Result<T, InterpretError> {
+    let garbage = format!("garbage following instruction on line {}", index + 1);
+    let operand = operands.next().ok_or_else(|| InterpretError::parse(index, garbage.clone()))?;
+    read(&mut std::iter::once(operand)).ok_or_else(|| InterpretError::parse_at(index, garbage, operand.trim()))
+}
+
+/// The registers the parser accepts: `r0` up to the number of registers, and the aliases defined so far.
+struct RegisterNames {
+    count: usize,
+    aliases: HashMap<String, Register>,
+}
+
+/// Reads a register, written as `rN` or as an alias.
+fn read_reg<'a>(operands: &mut impl Iterator<Item = &'a str>, index: usize, names: &RegisterNames) -> Result<Register, InterpretError> {
+    let operand = operands.next();
+    if let Some(&reg) = operand.and_then(|operand| names.aliases.get(operand.trim())) {
+        return Ok(reg);
+    }
+    let name = operand.map(str::trim).filter(|name| name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'));
+    match (read_next(&mut operand.into_iter(), index, read_reg_), name) {
+        (Ok(reg), _) if reg >= names.count => Err(InterpretError::UnknownRegister { line: index + 1, reg }),
+        (Ok(reg), _) => Ok(reg),
+        (Err(_), Some(name)) => {
+            Err(InterpretError::parse_at(index, format!("`{}` is neither a register nor an alias defined above line {}", name, index + 1), name))
+        },
+        (Err(error), None) => Err(error),
+    }
+}
+
+fn read_reg_<'a>(i: &mut impl Iterator<Item = &'a str>) -> Option<Register> {
+    let n = i.next()?.trim();
+    n.strip_prefix(['r', 'R'])?.parse().ok()
+}
+
+/// Reads a register, or an immediate optionally prefixed with `#`.
+fn read_operand<'a>(operands: &mut impl Iterator<Item = &'a str>, index: usize, names: &RegisterNames) -> Result<Operand, InterpretError> {
+    let operand = operands.next();
+    match operand.map(str::trim).filter(|operand| operand.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')) {
+        Some(_) => read_reg(&mut operand.into_iter(), index, names).map(Operand::Reg),
+        None => read_next(&mut operand.into_iter(), index, read_imm_)
+            .map(|value| Operand::Imm(Wrapping(value))),
+    }
+}
+
+fn read_imm<'a>(operands: &mut impl Iterator<Item = &'a str>, index: usize) -> Result<u64, InterpretError> {
+    read_next(operands, index, read_imm_)
+}
+
+/// Reads an immediate, which may be prefixed with `#`.
+fn read_imm_<'a>(i: &mut impl Iterator<Item = &'a str>) -> Option<u64> {
+    let imm = i.next()?.trim();
+    parse_value(imm.strip_prefix('#').unwrap_or(imm))
+}
+
+fn read_label<'a>(operands: &mut impl Iterator<Item = &'a str>, index: usize) -> Result<String, InterpretError> {
+    read_next(operands, index, read_label_)
+}
+
+fn read_label_<'a>(i: &mut impl Iterator<Item = &'a str>) -> Option<String> {
+    let n = i.next()?.trim();
+    Some(n.to_string())
+}
+
+/// Reads the double quoted string on the line `src`, which may only be followed by a comment. The string is taken
+/// from the line as written, so it keeps its case and may contain commas and comment markers.
+fn read_string(src: &str, index: usize) -> Result<String, InterpretError> {
+    let garbage = || InterpretError::parse(index, format!("garbage following instruction on line {}", index + 1));
+    let (_, quoted) = src.split_once('"').ok_or_else(garbage)?;
+    let mut string = String::new();
+    let mut chars = quoted.chars();
+    loop {
+        match chars.next().ok_or_else(garbage)? {
+            '"' => break,
+            '\\' => string.push(match chars.next().ok_or_else(garbage)? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                c => c,
+            }),
+            c => string.push(c),
+        }
+    }
+    let rest = chars.as_str().trim();
+    if !(rest.is_empty() || rest.starts_with("//") || rest.starts_with(';') || rest.starts_with('#')) {
+        return Err(garbage());
+    }
+    if !string.is_ascii() {
+        return Err(InterpretError::parse(index, format!("strings may only contain ASCII characters on line {}", index + 1)));
+    }
+    Ok(string)
+}
+
+/// Parses an immediate in the program or a value given on the command line: `0x`, `0b` or `0o` prefixed hex, binary
+/// or octal, a character in single quotes standing for its code, or a decimal number, which may be negative and is
+/// then stored in two's complement. Digits may be separated by underscores, as in `1_000_000`.
+pub fn parse_value(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if let Some(quoted) = value.strip_prefix('\'').and_then(|value| value.strip_suffix('\'')).filter(|quoted| !quoted.is_empty()) {
+        let c = match quoted {
+            "\\n" => '\n',
+            "\\t" => '\t',
+            "\\r" => '\r',
+            "\\0" => '\0',
+            "\\\\" => '\\',
+            "\\'" => '\'',
+            _ => {
+                let mut chars = quoted.chars();
+                let c = chars.next()?;
+                chars.next().is_none().then_some(c)?
+            },
+        };
+        return Some(c as u64);
+    }
+    let (radix, digits) = match value.get(..2).map(str::to_ascii_lowercase).as_deref() {
+        Some("0x") => (16, &value[2..]),
+        Some("0b") => (2, &value[2..]),
+        Some("0o") => (8, &value[2..]),
+        _ => (10, value),
+    };
+    // Underscores may separate digits but not start or end the number.
+    if digits.trim_start_matches('-').starts_with('_') || digits.ends_with('_') {
+        return None;
+    }
+    let digits = digits.replace('_', "");
+    match radix {
+        10 => digits.parse().ok().or_else(|| digits.parse::<i64>().ok().map(|i| i as u64)),
+        radix => u64::from_str_radix(&digits, radix).ok(),
+    }
+}
+
+pub fn interpret_arg(arg: String) -> Result<(usize, u64), String> {
+    let (before, after) = arg.split_once('=').ok_or_else(|| arg.clone())?;
+    let after = parse_value(after).ok_or_else(|| arg.clone())?;
+    if !(before.starts_with("r") || before.starts_with("R")) {
+        Err(arg)
+    }
+    else {
+        let reg = before[1..].parse().map_err(|_| arg.clone())?;
+        Ok((reg, after))
+    }
+}
+
+/// Reads and runs debugger commands from `input`, writing their output to `output`, until an empty line or the end of input. With `post_run` empty
+/// lines are ignored and only `quit` or the end of input leave the prompt. Lines that are not a command are
+/// parsed and executed as an instruction. Returns the program counter to continue from if it was changed, and
+/// `InterpretError::Quit` for `quit`. Mistyped commands are reported on `output` and the prompt continues.
+pub fn debug_prompt(state: &mut State, index: usize, post_run: bool, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<Option<usize>, InterpretError> {
+    loop {
+        if let Some(prompt) = &state.config.debug_prompt {
+            write!(output, "{}", prompt).map_err(io_error(index))?;
+            output.flush().map_err(io_error(index))?;
+        }
+        let mut line = String::new();
+        if input.read_line(&mut line).map_err(io_error(index))? == 0 {
+            return Ok(None);
+        }
+        let command = line.trim();
+        let (name, rest) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+        match name {
+            "" if post_run => (),
+            "" | "c" | "continue" if !post_run => {
+                state.single_step = false;
+                return Ok(None);
+            },
+            "s" | "step" if !post_run => {
+                state.single_step = true;
+                return Ok(None);
+            },
+            "c" | "continue" | "s" | "step" => prompt_error(output, index, "the program has finished")?,
+            "quit" | "q" => return Err(InterpretError::Quit { line: index + 1 }),
+            "help" => writeln!(output, "commands: c, s, dump, m first last, labels, set rN value, mark, goto-mark, save name, restore name, quit, or any instruction to execute it").map_err(io_error(index))?,
+            "mark" if post_run => prompt_error(output, index, "the program has finished")?,
+            "mark" => state.mark = Some(index),
+            "goto-mark" if post_run => prompt_error(output, index, "the program has finished")?,
+            "goto-mark" => match state.mark {
+                Some(mark) => return Ok(Some(mark)),
+                None => prompt_error(output, index, "no mark has been set")?,
+            },
+            "save" | "restore" if rest.is_empty() => prompt_error(output, index, &format!("`{}` expects the name of a snapshot", name))?,
+            "save" => {
+                let snapshot = state.snapshot();
+                state.snapshots.insert(rest.to_string(), snapshot);
+            },
+            "restore" => match state.snapshots.get(rest).cloned() {
+                Some(snapshot) => {
+                    state.restore(&snapshot);
+                    state.write_dump_compact(output).map_err(io_error(index))?;
+                },
+                None => prompt_error(output, index, &format!("no snapshot named `{}`", rest))?,
+            },
+            "dump" => state.write_dump(output).map_err(io_error(index))?,
+            "m" | "mem" => {
+                let bounds: Option<Vec<usize>> = rest.split_whitespace().map(|bound| parse_value(bound).map(|bound| bound as usize)).collect();
+                match bounds.as_deref() {
+                    Some(&[first, last]) if first <= last && last < state.memory.len() => {
+                        state.write_memory(output, first..last + 1).map_err(io_error(index))?;
+                    },
+                    Some(&[_, _]) => prompt_error(output, index, &format!("expected addresses in order and below {}", state.memory.len()))?,
+                    _ => prompt_error(output, index, "`m` expects the first and last address, e.g. `m 0 16`")?,
+                }
+            },
+            "labels" => state.write_labels(output).map_err(io_error(index))?,
+            "set" => match interpret_arg(rest.split_whitespace().collect::<Vec<_>>().join("=")) {
+                Ok((reg, _)) if reg >= state.registers.len() => prompt_error(output, index, &format!("r{} does not exist", reg))?,
+                Ok((reg, val)) => state[&reg] = Wrapping(val & state.register_mask()),
+                Err(arg) => prompt_error(output, index, &format!("Unable to parse register assignment: `{}`", arg))?,
+            },
+            _ => {
+                let instruction = Instruction::parse(state)((index, command));
+                match instruction {
+                    Ok(instruction) => match instruction.apply(state, index, input, output) {
+                        Ok(_) => {
+                            state.truncate_registers();
+                            state.write_dump_compact(output).map_err(io_error(index))?;
+                        },
+                        Err(error @ InterpretError::Quit { .. }) => return Err(error),
+                        Err(error) => prompt_error(output, index, &error.to_string())?,
+                    },
+                    Err(error) => prompt_error(output, index, &error.to_string())?,
+                }
+            },
+        }
+    }
+}
+
+/// Turns a failure to read input or write output while executing the instruction at `index` into an error.
+fn io_error(index: usize) -> impl Fn(io::Error) -> InterpretError {
+    move |error| InterpretError::Io { line: index + 1, message: error.to_string() }
+}
+
+pub fn print_warning(warning: &str) {
+    eprintln!("{} {}", ansi_term::Color::Yellow.paint("Warning:"), warning);
+}
+
+/// How errors are printed to stderr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// A colored `Error:` followed by the message.
+    #[default]
+    Human,
+    /// One JSON object per line with the kind, line and message of the error. Errors that are not an
+    /// `InterpretError` have the kind `Error` and a `null` line.
+    Json,
+}
+
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Sets the format used by `error_line` for the rest of the process.
+pub fn set_error_format(format: ErrorFormat) {
+    JSON_ERRORS.store(format == ErrorFormat::Json, Ordering::Relaxed);
+}
+
+/// The format set with `set_error_format`.
+pub fn error_format() -> ErrorFormat {
+    match JSON_ERRORS.load(Ordering::Relaxed) {
+        true => ErrorFormat::Json,
+        false => ErrorFormat::Human,
+    }
+}
+
+/// `error` as a line to print, in the format set with `set_error_format`.
+pub fn error_line(error: &str) -> String {
+    match error_format() {
+        ErrorFormat::Human => format!("{} {}", ansi_term::Color::Red.paint("Error: "), error),
+        ErrorFormat::Json => format!("{{\"kind\":\"Error\",\"line\":null,\"message\":\"{}\"}}", json::escape(error)),
+    }
+}
+
+/// Writes `error` to the `output` of the debug prompt at `index` as an `error_line`.
+fn prompt_error(output: &mut dyn Write, index: usize, error: &str) -> Result<(), InterpretError> {
+    writeln!(output, "{}", error_line(error)).map_err(io_error(index))
+}
@@ -1,282 +1,951 @@
-use std::{collections::HashMap, ops::{Index, IndexMut}, num::Wrapping};
-
-type Register = usize;
-type Label = String;
-
-struct State {
-    registers: [Wrapping<u64>; 8],
-    zero: bool,
-    labels: HashMap<String, usize>,
-}
-
-impl State {
-    fn new() -> State {
-        State {
-            registers: [Wrapping(0); 8],
-            zero: false,
-            labels: HashMap::new(),
-        }
-    }
-
-    fn add_label(&mut self, label: String, index: usize) {
-        self.labels.insert(label, index);
-    }
-
-    fn with_zero(&mut self, reg: &Register, value: Wrapping<u64>) {
-        self[reg] = value;
-        self.zero = value.0 == 0;
-    }
-
-    fn dump(&self) {
-        println!("Zero: {}", self.zero);
-        println!("                 unsigned                signed                 hex");
-        for i in 0..8 {
-            println!("R{i}:  {:width$}  {:width$}  0x{:016X}", self[&i], self[&i].0 as i64, self[&i], width = 20);
-        }
-    }
-}
-
-impl Index<&usize> for State {
-    type Output = Wrapping<u64>;
-
-    fn index(&self, index: &usize) -> &Self::Output {
-        &self.registers[*index]
-    }
-}
-
-impl IndexMut<&usize> for State {
-    fn index_mut(&mut self, index: &usize) -> &mut Self::Output {
-        &mut self.registers[*index]
-    }
-}
-
-#[derive(Debug)]
-enum Instruction {
-    Noop,
-    Debug,
-    Zero {
-        reg: Register,
-    },
-    Mov {
-        to: Register,
-        from: Register,
-    },
-    Add {
-        to: Register,
-        op1: Register,
-        op2: Register,
-    },
-    Sub {
-        to: Register,
-        op1: Register,
-        op2: Register,
-    },
-    Inc {
-        reg: Register,
-    },
-    Dec {
-        reg: Register,
-    },
-    And {
-        to: Register,
-        op1: Register,
-        op2: Register,
-    },
-    Or {
-        to: Register,
-        op1: Register,
-        op2: Register,
-    },
-    Xor {
-        to: Register,
-        op1: Register,
-        op2: Register,
-    },
-    Not {
-        reg: Register,
-    },
-    Shl {
-        reg: Register,
-        amount: u64,
-    },
-    Shr {
-        reg: Register,
-        amount: u64,
-    },
-    Jz {
-        label: Label,
-    },
-    Jnz {
-        label: Label,
-    },
-    J {
-        label: Label,
-    },
-}
-
-impl Instruction {
-    fn apply(&self, state: &mut State, index: usize) -> usize  {
-        match self {
-            Instruction::Noop => (),
-            Instruction::Debug => {
-                println!("{} {}", ansi_term::Color::Yellow.paint("Debug:"), ansi_term::Color::Blue.paint(&format!("line {}", index + 1)));
-                state.dump();
-                let mut s = String::new();
-                report_error_if_none(std::io::stdin().read_line(&mut s).ok(), "IO error. Did you close stdin?");
-            }
-            Instruction::Zero { reg } => state[reg] = Wrapping(0),
-            Instruction::Mov { to, from } => state[to] = state[from],
-            Instruction::Add { to, op1, op2 } => state.with_zero(to, state[op1] + state[op2]),
-            Instruction::Sub { to, op1, op2 } => state.with_zero(to, state[op1] - state[op2]),
-            Instruction::Inc { reg } => state.with_zero(reg, state[reg] + Wrapping(1)),
-            Instruction::Dec { reg } => state.with_zero(reg, state[reg] - Wrapping(1)),
-            Instruction::And { to, op1, op2 } => state.with_zero(to, state[op1] & state[op2]),
-            Instruction::Or { to, op1, op2 } => state.with_zero(to, state[op1] | state[op2]),
-            Instruction::Xor { to, op1, op2 } => state.with_zero(to, state[op1] ^ state[op2]),
-            Instruction::Not { reg } => state.with_zero(reg, !state[reg]),
-            Instruction::Shl { reg, amount } => state[reg] = Wrapping(state[reg].0 << amount),
-            Instruction::Shr { reg, amount } => state[reg] = Wrapping(state[reg].0 >> amount),
-            Instruction::Jz { label } => if state.zero { return *report_error_if_none(state.labels.get(label), &format!("unknown label `{}` on line {}", label, index + 1)) },
-            Instruction::Jnz { label } => if !state.zero { return *report_error_if_none(state.labels.get(label), &format!("unknown label `{}` on line {}", label, index + 1)) },
-            Instruction::J { label } => return *report_error_if_none(state.labels.get(label), &format!("unknown label `{}` on line {}", label, index + 1)),
-        }
-        index + 1
-    }
-
-    fn parse<'a>(state: &'a mut State) -> impl FnMut((usize, &str)) -> Instruction + 'a {
-        |(index, src)| {
-            let lowercase = src.to_lowercase();
-            let code = lowercase.split_once("//").map(|(a, _)| a).unwrap_or(&lowercase);
-            let code = lowercase.split_once(";").map(|(a, _)| a).unwrap_or(&code);
-            let code = lowercase.split_once("#").map(|(a, _)| a).unwrap_or(&code);
-            let mut split = code.split_whitespace();
-            let first = split.next();
-            let operands = split.collect::<Vec<_>>().join(" ");
-            let mut operands = operands.split(",");
-            let val =match first {
-                Some(first) => match first.trim() {
-                    "zero" => Instruction::Zero { reg: read_reg(&mut operands, index) },
-                    "debug" => Instruction::Debug,
-                    "mov" => Instruction::Mov { to: read_reg(&mut operands, index), from: read_reg(&mut operands, index) },
-                    "add" => Instruction::Add { to: read_reg(&mut operands, index), op1: read_reg(&mut operands, index), op2: read_reg(&mut operands, index) },
-                    "sub" => Instruction::Sub { to: read_reg(&mut operands, index), op1: read_reg(&mut operands, index), op2: read_reg(&mut operands, index) },
-                    "inc" => Instruction::Inc { reg: read_reg(&mut operands, index) },
-                    "dec" => Instruction::Dec { reg: read_reg(&mut operands, index) },
-                    "and" => Instruction::And { to: read_reg(&mut operands, index), op1: read_reg(&mut operands, index), op2: read_reg(&mut operands, index) },
-                    "or" => Instruction::Or { to: read_reg(&mut operands, index), op1: read_reg(&mut operands, index), op2: read_reg(&mut operands, index) },
-                    "xor" => Instruction::Xor { to: read_reg(&mut operands, index), op1: read_reg(&mut operands, index), op2: read_reg(&mut operands, index) },
-                    "not" => Instruction::Not { reg: read_reg(&mut operands, index) },
-                    "shl" => Instruction::Shl { reg: read_reg(&mut operands, index), amount: read_imm(&mut operands, index) },
-                    "shr" => Instruction::Shr { reg: read_reg(&mut operands, index), amount: read_imm(&mut operands, index) },
-                    "jz" => Instruction::Jz { label: read_label(&mut operands, index) },
-                    "jnz" => Instruction::Jnz { label: read_label(&mut operands, index) },
-                    "j" => Instruction::J { label: read_label(&mut operands, index) },
-                    label => return { state.add_label(report_error_if_none(label.split_once(":"), &format!("garbage instruction `{}`", label)).0.to_string(), index); Instruction::parse(state)((index, &operands.collect::<Vec<_>>().join(","))) },
-                },
-                None => Instruction::Noop,
-            };
-            if operands.filter(|k| !k.trim().is_empty()).count() > 0 {
-                report_error(&format!("Too many operands on line {}", index + 1));
-            }
-            val
+use std::{collections::{HashMap, HashSet}, fs::File, io::{self, BufRead, BufReader, IsTerminal, Read, Write}, num::Wrapping, sync::atomic::{AtomicBool, Ordering}, time::{Duration, Instant}};
+
+use basic_asm_interpreter::{debug_prompt, error_format, error_line, interpret_arg, is_directive, parse_value, print_warning, run, run_coop, set_error_format, DumpFormat, ErrorFormat, Instruction, InterpretError, State, MEMORY_WORDS};
+
+/// Number of `(pc, fingerprint)` pairs remembered by `--stuck-detect` before the history is forgotten.
+const STUCK_DETECT_CAPACITY: usize = 1 << 20;
+
+/// Number of instructions a program may execute with `--sandbox` before it is stopped.
+const SANDBOX_MAX_STEPS: u64 = 1_000_000;
+
+/// Number of bytes a program may print with `--sandbox` unless changed with `--limit-output`.
+const SANDBOX_LIMIT_OUTPUT: usize = 1 << 20;
+
+/// Number of words of memory a program may have with `--sandbox` unless changed with `--max-mem-words`.
+const SANDBOX_MAX_MEM_WORDS: usize = 1 << 16;
+
+/// Number of instructions a program may execute before it is stopped unless changed with `--max-steps`.
+const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+
+/// Number of steps between the checks of the elapsed time for `--timeout`.
+const TIMEOUT_CHECK_INTERVAL: u64 = 4096;
+
+/// Size of the buffer `--record` reads stdin into.
+const RECORD_BUFFER_BYTES: usize = 8192;
+
+/// Printed before the commands read at the debug prompt unless changed with `--debug-prompt`.
+const DEFAULT_DEBUG_PROMPT: &str = "(debug) ";
+
+/// Milliseconds `--animate` pauses after every dump unless changed with `--delay`.
+const DEFAULT_ANIMATE_DELAY: u64 = 500;
+
+/// Set by the first Ctrl-C and checked before every instruction.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Default)]
+struct Options {
+    source: Option<String>,
+    registers: Vec<(usize, u64)>,
+    dump_every: Option<u64>,
+    emit_resolved: bool,
+    parse_only: bool,
+    coop: bool,
+    /// Programs run together with `source` by `--coop`.
+    coop_sources: Vec<String>,
+    disasm_hex: bool,
+    stuck_detect: bool,
+    no_dump: bool,
+    quiet: bool,
+    load_state: Option<String>,
+    init_state: Option<String>,
+    save_state: Option<String>,
+    diff_state: Option<String>,
+    interactive: bool,
+    label_output: bool,
+    soft_memory: bool,
+    trace_jumps: bool,
+    warn_uninit: bool,
+    warn_fallthrough: bool,
+    warn_unreachable: bool,
+    case_insensitive: bool,
+    strict_overflow: bool,
+    dump_format: DumpFormat,
+    sandbox: bool,
+    foreach: bool,
+    skip_bad: bool,
+    explain: bool,
+    max_line_len: Option<usize>,
+    memory_words: Option<usize>,
+    max_mem_words: Option<usize>,
+    limit_output: Option<usize>,
+    stats: bool,
+    initial_sp: Option<usize>,
+    record: Option<String>,
+    replay: Option<String>,
+    check_contract: bool,
+    annotate_errors: bool,
+    one_based_regs: bool,
+    debug_only: Option<Vec<String>>,
+    debug_prompt: Option<String>,
+    animate: Option<u64>,
+    delay: Option<u64>,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    trace: bool,
+    strict_comments: bool,
+    json: bool,
+    trap_addr: Option<usize>,
+    register_count: Option<usize>,
+    width: Option<u32>,
+    dump_radix: Option<u32>,
+    dump_memory: usize,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options::default();
+    let mut args = std::env::args().skip(1).flat_map(|arg| match arg.split_once('=') {
+        Some((flag, value)) if flag.starts_with("--") => vec![flag.to_string(), value.to_string()],
+        _ => vec![arg],
+    });
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dump-every" => {
+                let n = read_flag_value(&mut args, &arg);
+                if n == 0 {
+                    report_error("`--dump-every` expects a positive number of steps");
+                }
+                options.dump_every = Some(n);
+            },
+            "--animate" => {
+                let n = read_flag_value(&mut args, &arg);
+                if n == 0 {
+                    report_error("`--animate` expects a positive number of steps");
+                }
+                options.animate = Some(n);
+            },
+            "--max-steps" => options.max_steps = Some(read_flag_value(&mut args, &arg)),
+            "--timeout" => {
+                let seconds: f64 = read_flag_value(&mut args, &arg);
+                if !(seconds.is_finite() && seconds > 0.0) {
+                    report_error("`--timeout` expects a positive number of seconds");
+                }
+                options.timeout = Some(Duration::from_secs_f64(seconds));
+            },
+            "--registers" => {
+                let count = read_flag_value(&mut args, &arg);
+                if count == 0 {
+                    report_error("`--registers` expects a positive number of registers");
+                }
+                options.register_count = Some(count);
+            },
+            "--width" => {
+                let width = read_flag_value(&mut args, &arg);
+                if ![8, 16, 32, 64].contains(&width) {
+                    report_error("`--width` expects 8, 16, 32 or 64");
+                }
+                options.width = Some(width);
+            },
+            "--dump-mem" => options.dump_memory = read_flag_value(&mut args, &arg),
+            "--dump-radix" => {
+                let radix = read_flag_value(&mut args, &arg);
+                if ![2, 8, 10, 16].contains(&radix) {
+                    report_error("`--dump-radix` expects 2, 8, 10 or 16");
+                }
+                options.dump_radix = Some(radix);
+            },
+            "--trap-addr" => options.trap_addr = Some(read_flag_value(&mut args, &arg)),
+            "--delay" => options.delay = Some(read_flag_value(&mut args, &arg)),
+            "--emit-resolved" => options.emit_resolved = true,
+            "--parse-only" => options.parse_only = true,
+            "--coop" => options.coop = true,
+            "--disasm-hex" => options.disasm_hex = true,
+            "--stuck-detect" => options.stuck_detect = true,
+            "--no-dump" => options.no_dump = true,
+            "--quiet" => options.quiet = true,
+            "--interactive" => options.interactive = true,
+            "--label-output" => options.label_output = true,
+            "--soft-memory" => options.soft_memory = true,
+            "--trace" => options.trace = true,
+            "--trace-jumps" => options.trace_jumps = true,
+            "--warn-uninit" => options.warn_uninit = true,
+            "--warn-fallthrough" => options.warn_fallthrough = true,
+            "--warn-unreachable" => options.warn_unreachable = true,
+            "--case-insensitive" => options.case_insensitive = true,
+            "--strict-overflow" => options.strict_overflow = true,
+            "--sandbox" => options.sandbox = true,
+            "--foreach" => options.foreach = true,
+            "--skip-bad" => options.skip_bad = true,
+            "--explain" => options.explain = true,
+            "--check-contract" => options.check_contract = true,
+            "--annotate-errors" => options.annotate_errors = true,
+            "--strict-comments" => options.strict_comments = true,
+            "--one-based-regs" => options.one_based_regs = true,
+            "--debug-only" => {
+                let tags = read_flag_value::<String>(&mut args, &arg);
+                options.debug_only = Some(tags.split(',').map(|tag| tag.trim().to_lowercase()).collect());
+            },
+            "--debug-prompt" => options.debug_prompt = Some(read_flag_value(&mut args, &arg)),
+            "--max-line-len" => options.max_line_len = Some(read_flag_value(&mut args, &arg)),
+            "--memory-words" => options.memory_words = Some(read_flag_value(&mut args, &arg)),
+            "--max-mem-words" => options.max_mem_words = Some(read_flag_value(&mut args, &arg)),
+            "--limit-output" => options.limit_output = Some(read_flag_value(&mut args, &arg)),
+            "--stats" => options.stats = true,
+            "--json" => options.json = true,
+            "--format" => options.dump_format = match read_flag_value::<String>(&mut args, &arg).as_str() {
+                "table-wide" => DumpFormat::TableWide,
+                "table-narrow" => DumpFormat::TableNarrow,
+                format => report_error(&format!("unknown dump format `{}`, expected `table-wide` or `table-narrow`", format)),
+            },
+            "--error-format" => set_error_format(match read_flag_value::<String>(&mut args, &arg).as_str() {
+                "human" => ErrorFormat::Human,
+                "json" => ErrorFormat::Json,
+                format => report_error(&format!("unknown error format `{}`, expected `human` or `json`", format)),
+            }),
+            "--load-state" => options.load_state = Some(read_flag_value(&mut args, &arg)),
+            "--state" => options.init_state = Some(read_flag_value(&mut args, &arg)),
+            "--save-state" => options.save_state = Some(read_flag_value(&mut args, &arg)),
+            "--diff-state" => options.diff_state = Some(read_flag_value(&mut args, &arg)),
+            "--record" => options.record = Some(read_flag_value(&mut args, &arg)),
+            "--replay" => options.replay = Some(read_flag_value(&mut args, &arg)),
+            _ if options.source.is_none() => options.source = Some(arg),
+            _ if !arg.contains('=') && !arg.starts_with('-') => options.coop_sources.push(arg),
+            _ if arg.to_lowercase().starts_with("sp=") => {
+                let sp = parse_value(&arg[3..]).and_then(|sp| usize::try_from(sp).ok());
+                options.initial_sp = Some(report_error_if_none(sp, &format!("Unable to parse arg: `{}`", arg)));
+            },
+            _ => match interpret_arg(arg) {
+                Ok(init) => options.registers.push(init),
+                Err(arg) => report_error(&format!("Unable to parse arg: `{}`", arg)),
+            },
+        }
+    }
+    if options.foreach && (options.save_state.is_some() || options.diff_state.is_some() || options.interactive || options.stats) {
+        report_error("--foreach has no single final state, so it cannot be used with --save-state, --diff-state, --interactive or --stats");
+    }
+    if options.sandbox {
+        options.limit_output.get_or_insert(SANDBOX_LIMIT_OUTPUT);
+        options.max_mem_words.get_or_insert(SANDBOX_MAX_MEM_WORDS);
+    }
+    if let Some(max) = options.max_mem_words {
+        let words = options.memory_words.unwrap_or(MEMORY_WORDS);
+        if words > max {
+            report_error(&format!("memory of {} words exceeds the limit of {} words set with --max-mem-words", words, max));
+        }
+    }
+    if let Some(arg) = options.coop_sources.first().filter(|_| !options.coop) {
+        report_error(&format!("Unable to parse arg: `{}`", arg));
+    }
+    if options.coop {
+        let single = [
+            (options.foreach, "--foreach"),
+            (options.interactive, "--interactive"),
+            (options.json, "--json"),
+            (options.stats, "--stats"),
+            (options.save_state.is_some(), "--save-state"),
+            (options.diff_state.is_some(), "--diff-state"),
+            (options.trace, "--trace"),
+            (options.trace_jumps, "--trace-jumps"),
+            (options.explain, "--explain"),
+            (options.check_contract, "--check-contract"),
+            (options.warn_fallthrough, "--warn-fallthrough"),
+            (options.stuck_detect, "--stuck-detect"),
+            (options.animate.is_some(), "--animate"),
+            (options.dump_every.is_some(), "--dump-every"),
+            (options.label_output, "--label-output"),
+        ];
+        if let Some((_, option)) = single.iter().find(|&&(used, _)| used) {
+            report_error(&format!("--coop runs several programs, so it cannot be used with {}", option));
+        }
+    }
+    if options.json && (options.foreach || options.stats) {
+        report_error("--json prints only the final state, so it cannot be used with --foreach or --stats");
+    }
+    if options.delay.is_some() && options.animate.is_none() {
+        report_error("--delay sets the pause between the dumps of --animate and cannot be used without it");
+    }
+    if options.record.is_some() && options.replay.is_some() {
+        report_error("--record and --replay cannot be used together");
+    }
+    if options.sandbox {
+        if options.save_state.is_some() {
+            report_error("--save-state writes a file and cannot be used with --sandbox");
+        }
+        if options.record.is_some() {
+            report_error("--record writes a file and cannot be used with --sandbox");
+        }
+        if options.interactive {
+            report_error("--interactive reads input and cannot be used with --sandbox");
         }
     }
+    options
 }
 
-fn read_reg<'a>(operands: &mut impl Iterator<Item = &'a str>, index: usize) -> Register {
-    match read_reg_(operands) {
-        Some(reg) => {
-            if reg >= 8 {
-                report_error(&format!("r{} does not exist", reg));
-            }
-            reg
+fn print_error(error: &str) {
+    eprintln!("{}", error_line(error));
+}
+
+fn report_error(error: &str) -> ! {
+    print_error(error);
+    std::process::exit(1);
+}
+
+fn report_error_if_none<T>(opt: Option<T>, error: &str) -> T {
+    match opt {
+        Some(v) => v,
+        None => report_error(error),
+    }
+}
+
+/// Exits with an error if writing output failed.
+fn written(result: io::Result<()>) {
+    report_error_if_none(result.ok(), "unable to write output")
+}
+
+/// Prints the problem that stopped the program and exits. Quitting at the debug prompt is not a problem, so it exits
+/// successfully without printing anything.
+fn report_run_error(error: &InterpretError) -> ! {
+    if let InterpretError::Quit { .. } = error {
+        std::process::exit(0);
+    }
+    if error_format() == ErrorFormat::Json {
+        eprintln!("{}", error.to_json());
+        std::process::exit(1);
+    }
+    report_error(&error.to_string())
+}
+
+/// Prints the problems with the program in `source` and exits.
+fn report_load_error(error: &InterpretError, source: &str, options: &Options) -> ! {
+    if error_format() == ErrorFormat::Json {
+        report_run_error(error);
+    }
+    for error in error.errors() {
+        print_error(&error.to_string());
+        if let Some(annotation) = error.annotate(source).filter(|_| options.annotate_errors) {
+            eprintln!("{}", annotation);
+        }
+    }
+    std::process::exit(1);
+}
+
+fn read_flag_value<T: std::str::FromStr>(args: &mut impl Iterator<Item = String>, flag: &str) -> T {
+    report_error_if_none(args.next().and_then(|v| v.parse().ok()), &format!("`{}` expects a value", flag))
+}
+
+/// Reads the program at `path`, decompressing it first if it is gzip compressed, which is recognized by a `.gz`
+/// extension or the gzip magic bytes.
+fn get_source(path: Option<&str>) -> Option<String> {
+    let path = path?;
+    let bytes = std::fs::read(path).ok()?;
+    if !path.ends_with(".gz") && !bytes.starts_with(&[0x1f, 0x8b]) {
+        return String::from_utf8(bytes).ok();
+    }
+    let mut source = String::new();
+    let decompressed = flate2::read::GzDecoder::new(bytes.as_slice()).read_to_string(&mut source);
+    report_error_if_none(decompressed.ok(), &format!("unable to decompress `{}`", path));
+    Some(source)
+}
+
+/// Writes the output of the program to `inner` and fails once it would exceed `limit` bytes.
+struct LimitedOutput<W> {
+    inner: W,
+    limit: Option<usize>,
+    written: usize,
+}
+
+impl<W: Write> LimitedOutput<W> {
+    fn new(inner: W, limit: Option<usize>) -> LimitedOutput<W> {
+        LimitedOutput { inner, limit, written: 0 }
+    }
+}
+
+impl<W: Write> Write for LimitedOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(limit) = self.limit.filter(|&limit| self.written + buf.len() > limit) {
+            return Err(io::Error::other(format!("output limit of {} bytes reached", limit)));
+        }
+        let n = self.inner.write(buf)?;
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads from stdin and appends everything that is consumed to a log, so the session can be replayed. It buffers the
+/// input itself, so what is consumed is logged from the buffer.
+struct Recorder<R> {
+    inner: R,
+    log: File,
+    buf: Vec<u8>,
+    /// The buffered bytes not consumed yet are `buf[pos..filled]`.
+    pos: usize,
+    filled: usize,
+}
+
+impl<R: Read> Recorder<R> {
+    fn new(inner: R, log: File) -> Recorder<R> {
+        Recorder { inner, log, buf: vec![0; RECORD_BUFFER_BYTES], pos: 0, filled: 0 }
+    }
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for Recorder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.filled {
+            self.filled = self.inner.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let end = (self.pos + amt).min(self.filled);
+        if self.log.write_all(&self.buf[self.pos..end]).is_err() {
+            report_error("unable to write to the record file");
+        }
+        self.pos = end;
+    }
+}
+
+/// Opens the input of the program: the file given to `--replay`, or stdin, recorded to the file given to `--record`.
+fn open_input(options: &Options) -> Box<dyn BufRead> {
+    if let Some(path) = &options.replay {
+        let file = report_error_if_none(File::open(path).ok(), &format!("unable to read replay file `{}`", path));
+        return Box::new(BufReader::new(file));
+    }
+    let stdin = io::stdin().lock();
+    match &options.record {
+        Some(path) => {
+            let log = report_error_if_none(File::create(path).ok(), &format!("unable to write record file `{}`", path));
+            Box::new(Recorder::new(stdin, log))
         },
-        None => report_error(&format!("garbage following instruction on line {}", index + 1)),
+        None => Box::new(stdin),
     }
 }
 
-fn read_reg_<'a>(i: &mut impl Iterator<Item = &'a str>) -> Option<Register> {
-    let n = i.next()?.trim();
-    n.starts_with("r").then(|| {
-        n[1..].parse().ok()
-    }).flatten()
+/// Prints a compact dump if the number of executed steps is a positive multiple of `n`.
+fn dump_every(state: &State, n: Option<u64>) {
+    if let Some(n) = n {
+        if state.steps > 0 && state.steps.is_multiple_of(n) {
+            print!("{} ", ansi_term::Color::Yellow.paint(format!("Step {}:", state.steps)));
+            written(state.dump_compact());
+        }
+    }
 }
 
-fn read_imm<'a>(operands: &mut impl Iterator<Item = &'a str>, index: usize) -> u64 {
-    match read_imm_(operands) {
-        Some(v) => v,
-        None => report_error(&format!("garbage following instruction on line {}", index + 1)),
+/// Prints a compact dump and pauses for `delay` milliseconds after every `n` executed steps.
+fn animate(state: &State, n: Option<u64>, delay: u64) {
+    if let Some(n) = n {
+        if state.steps > 0 && state.steps.is_multiple_of(n) {
+            written(state.dump_compact());
+            std::thread::sleep(std::time::Duration::from_millis(delay));
+        }
     }
 }
 
-fn read_imm_<'a>(i: &mut impl Iterator<Item = &'a str>) -> Option<u64> {
-    let n = i.next()?.trim();
-    n.parse().ok()
+/// Logs the jump from `from` to `to` if the instruction at `from` is a jump that did not fall through.
+fn trace_jump(instructions: &[Instruction], lines: &[&str], from: usize, to: usize) {
+    let Some(condition) = instructions[from].condition() else { return };
+    if to == from + 1 {
+        return;
+    }
+    let target = if to == instructions.len() { "end".to_string() } else { format!("line {}", to + 1) };
+    eprintln!("{} line {}: `{}` ({}) -> {}", ansi_term::Color::Cyan.paint("Jump:"), from + 1, lines[from].trim(), condition, target);
 }
 
-fn read_label<'a>(operands: &mut impl Iterator<Item = &'a str>, index: usize) -> String {
-    match read_label_(operands) {
-        Some(v) => v,
-        None => report_error(&format!("garbage following instruction on line {}", index + 1)),
+/// Logs the instruction at `pc` before it is executed. Lines without an instruction are left out.
+fn trace(instruction: &Instruction, lines: &[&str], pc: usize) {
+    if !matches!(instruction, Instruction::Noop) {
+        let canonical = instruction.to_string().split_whitespace().collect::<Vec<_>>().join(" ");
+        eprintln!("{} pc {}, line {}: `{}` as `{}`", ansi_term::Color::Blue.paint("Trace:"), pc, pc + 1, lines[pc].trim(), canonical);
     }
 }
 
-fn read_label_<'a>(i: &mut impl Iterator<Item = &'a str>) -> Option<String> {
-    let n = i.next()?.trim();
-    Some(n.to_string())
+/// Logs what the instruction at `from` did, going from `before` to `after` and continuing at `to`.
+fn explain(instructions: &[Instruction], lines: &[&str], from: usize, before: &State, after: &State, to: usize) {
+    let explanation = instructions[from].explain(before, after, to, from);
+    eprintln!("{} line {}: `{}`: {}", ansi_term::Color::Purple.paint("Explain:"), from + 1, lines[from].trim(), explanation);
 }
 
-fn get_source() -> Option<String> {
-    std::fs::read_to_string(std::env::args().nth(1)?).ok()
+/// The line announcing that the program finished, with its name if `label` is given.
+fn finished_banner(label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{} {}", ansi_term::Color::Green.paint("Finished:"), label),
+        None => ansi_term::Color::Green.paint("Finished:").to_string(),
+    }
 }
 
-fn interpret_arg(arg: String) -> Result<(usize, u64), String> {
-    let (before, after) = arg.split_once('=').ok_or_else(|| arg.clone())?;
-    let after = after.parse().or_else(|_| after.parse::<i64>().map(|i| i as u64)).map_err(|_| arg.clone())?;
-    if !(before.starts_with("r") || before.starts_with("R")) {
-        Err(arg)
+/// Prints how many resources the finished program used.
+fn print_stats(state: &State, instructions: &[Instruction]) {
+    println!("{}", ansi_term::Color::Green.paint("Stats:"));
+    println!("Steps: {}", state.steps);
+    println!("Instructions: {}", instructions.len());
+    println!("Memory words: {}", state.memory.len());
+    println!("Peak stack depth: {}", state.peak_stack_depth);
+    let mut counts: HashMap<&str, u64> = HashMap::new();
+    for (instruction, &count) in instructions.iter().zip(&state.executions) {
+        *counts.entry(instruction.mnemonic().unwrap_or("(no instruction)")).or_default() += count;
     }
-    else {
-        let reg = before[1..].parse().map_err(|_| arg.clone())?;
-        if reg >= 8 {
-            report_error(&format!("r{} does not exist", reg));
+    let mut counts: Vec<_> = counts.into_iter().filter(|&(_, count)| count != 0).collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    println!("Executed:");
+    for (mnemonic, count) in counts {
+        println!("  {}: {}", mnemonic, count);
+    }
+}
+
+/// Warns about registers read by an instruction before any instruction above it writes them. This ignores control
+/// flow, so a register written further down and read after jumping back is reported too.
+fn warn_uninit(instructions: &[Instruction], registers: usize, initialized: impl IntoIterator<Item = usize>) {
+    let mut written = vec![false; registers];
+    for reg in initialized {
+        written[reg] = true;
+    }
+    for (index, instruction) in instructions.iter().enumerate() {
+        for reg in instruction.sources(registers) {
+            if !written[reg] {
+                print_warning(&format!("r{} is read on line {} before it is written", reg, index + 1));
+                written[reg] = true;
+            }
+        }
+        for reg in instruction.destinations() {
+            written[reg] = true;
         }
-        Ok((reg, after))
     }
 }
 
-fn report_error_if_none<T>(opt: Option<T>, error: &str) -> T {
-    match opt {
-        Some(v) => v,
-        None => report_error(error),
+/// Warns about every instruction that no path from the first line leads to, such as one after a `j` without a label.
+fn warn_unreachable(state: &State, instructions: &[Instruction]) {
+    let mut reachable = vec![false; instructions.len()];
+    let mut pending = vec![0];
+    while let Some(index) = pending.pop() {
+        if index >= instructions.len() || reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+        pending.extend(instructions[index].successors(state, index));
+    }
+    for (index, instruction) in instructions.iter().enumerate() {
+        if !reachable[index] && !matches!(instruction, Instruction::Noop) {
+            print_warning(&format!("line {} is unreachable", index + 1));
+        }
     }
 }
 
-fn report_error(error: &str) -> ! {
-    eprintln!("{} {}", ansi_term::Color::Red.paint("Error: "), error);
-    std::process::exit(1);
+/// Makes the first Ctrl-C stop the program before the next instruction and a second one exit immediately.
+fn install_interrupt_handler() {
+    let installed = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    });
+    if installed.is_err() {
+        print_warning("unable to install the Ctrl-C handler");
+    }
+}
+
+/// Dumps the state and exits if Ctrl-C was pressed.
+fn check_interrupted(state: &State, pc: usize) {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        println!("{} {}", ansi_term::Color::Red.paint("Interrupted:"), ansi_term::Color::Blue.paint(format!("line {}", pc + 1)));
+        written(state.dump());
+        std::process::exit(130);
+    }
+}
+
+/// Whether more than `timeout` has passed since `start`. The clock is only read every `TIMEOUT_CHECK_INTERVAL` steps.
+fn timed_out(start: Instant, timeout: Option<Duration>, steps: u64) -> bool {
+    match timeout {
+        Some(timeout) => steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && start.elapsed() > timeout,
+        None => false,
+    }
+}
+
+/// Runs `instructions` to the end, doing the per step work requested in `options`. `initialized` holds the
+/// registers set before the program starts, for checking the `.input` contract.
+fn execute(
+    state: &mut State,
+    instructions: &[Instruction],
+    lines: &[&str],
+    options: &Options,
+    initialized: Vec<bool>,
+    input: &mut dyn BufRead,
+) {
+    let mut visited = HashSet::new();
+    let mut previous = None;
+    let mut before: Option<State> = None;
+    let mut set = initialized;
+    let mut written = vec![false; state.registers.len()];
+    // Lines with a label that falling through into has not been warned about yet, with the first of their labels.
+    let mut fallthrough: HashMap<usize, String> = HashMap::new();
+    // A limit of 0 turns it off.
+    let max_steps = match options.max_steps.unwrap_or(DEFAULT_MAX_STEPS) {
+        0 => u64::MAX,
+        limit => limit,
+    };
+    let start = Instant::now();
+    if options.warn_fallthrough {
+        for (name, &target) in &state.labels {
+            let first = fallthrough.entry(target).or_insert_with(|| name.clone());
+            if name < first {
+                *first = name.clone();
+            }
+        }
+    }
+    let mut output = LimitedOutput::new(io::stdout(), options.limit_output);
+    let result = run(state, instructions, input, &mut output, |state, pc, instruction| {
+        check_interrupted(state, pc);
+        if options.sandbox && state.steps == SANDBOX_MAX_STEPS {
+            report_error(&format!("step limit of {} reached in sandbox mode at line {}", SANDBOX_MAX_STEPS, pc + 1));
+        }
+        if state.steps == max_steps {
+            report_error(&format!("step limit of {} reached at line {}, raise it with --max-steps", max_steps, pc + 1));
+        }
+        if timed_out(start, options.timeout, state.steps) {
+            report_error(&format!("timeout of {:?} reached at line {}", options.timeout.unwrap(), pc + 1));
+        }
+        dump_every(state, options.dump_every);
+        animate(state, options.animate, options.delay.unwrap_or(DEFAULT_ANIMATE_DELAY));
+        if options.trace {
+            trace(instruction, lines, pc);
+        }
+        if let Some(from) = previous.filter(|_| options.trace_jumps) {
+            trace_jump(instructions, lines, from, pc);
+        }
+        if let (Some(from), Some(before)) = (previous, &before) {
+            explain(instructions, lines, from, before, state, pc);
+        }
+        if options.explain {
+            before = Some(state.clone());
+        }
+        if let Some(from) = previous.filter(|&from| pc == from + 1) {
+            let jumped = instructions[from].labels().iter().any(|label| state.resolve_label(label, from) == Ok(pc));
+            if let Some(label) = fallthrough.remove(&pc).filter(|_| !jumped) {
+                print_warning(&format!("line {} falls through into label `{}` on line {}", from + 1, label, pc + 1));
+            }
+        }
+        previous = Some(pc);
+        if options.stuck_detect {
+            if visited.len() == STUCK_DETECT_CAPACITY {
+                visited.clear();
+            }
+            if !visited.insert((pc, state.fingerprint())) {
+                report_error(&format!("no-progress loop detected at line {}", pc + 1));
+            }
+        }
+        if options.check_contract {
+            for reg in instruction.sources(state.registers.len()) {
+                if state.inputs.contains(&reg) && !set[reg] {
+                    report_error(&format!("input r{} is read on line {} before it is set", reg, pc + 1));
+                }
+            }
+            for reg in instruction.destinations() {
+                set[reg] = true;
+                written[reg] = true;
+            }
+        }
+    });
+    if let Err(error) = result {
+        report_run_error(&error);
+    }
+    dump_every(state, options.dump_every);
+    if let Some(from) = previous.filter(|_| options.trace_jumps) {
+        trace_jump(instructions, lines, from, instructions.len());
+    }
+    if let (Some(from), Some(before)) = (previous, &before) {
+        explain(instructions, lines, from, before, state, instructions.len());
+    }
+    if options.check_contract {
+        for &reg in state.outputs.iter().filter(|&&reg| !written[reg]) {
+            print_warning(&format!("output r{} is never written", reg));
+        }
+    }
+}
+
+/// Runs the program once for every line of the input, starting from `template` with the registers set to the values on
+/// the line, and prints the resulting state after each run.
+fn foreach(template: &State, instructions: &[Instruction], lines: &[&str], options: &Options) {
+    // The lines are read from the same input the program reads from, so a `debug` prompt consumes lines too.
+    let mut stdin = open_input(options);
+    for number in 0.. {
+        let mut input = String::new();
+        if report_error_if_none(stdin.read_line(&mut input).ok(), "unable to read input") == 0 {
+            break;
+        }
+        let input = input.trim_end_matches(['\n', '\r']);
+        let values: Option<Vec<u64>> = input.split_whitespace().map(parse_value).collect();
+        let values = match values.filter(|values| values.len() <= template.registers.len()) {
+            Some(values) => values,
+            None if options.skip_bad => {
+                print_warning(&format!("skipping malformed input line {}: `{}`", number + 1, input));
+                continue;
+            },
+            None => report_error(&format!("malformed input line {}: `{}`", number + 1, input)),
+        };
+        let mut state = template.clone();
+        state.resize_registers(template.registers.len());
+        let initialized = (0..template.registers.len()).map(|i| i < values.len()).collect();
+        for (i, value) in values.into_iter().enumerate() {
+            state[&i] = Wrapping(value);
+        }
+        execute(&mut state, instructions, lines, options, initialized, &mut stdin);
+        if !options.quiet && !options.no_dump {
+            written(state.dump_compact());
+        }
+    }
+}
+
+/// Runs the program in `first` together with the programs given with `--coop`, one instruction of each in turn, and
+/// dumps the state of each of them at the end. The programs share the memory, data directives of later programs
+/// placing their data after that of the earlier ones.
+fn coop(first: State, instructions: Vec<Instruction>, options: &Options) {
+    let mut names = vec![options.source.clone().unwrap_or_default()];
+    let mut states = vec![first];
+    let mut programs = vec![instructions];
+    for path in &options.coop_sources {
+        let content = report_error_if_none(get_source(Some(path)), &format!("unable to read `{}`", path));
+        let previous = states.last_mut().unwrap();
+        let mut state = State::new();
+        state.config = previous.config.clone();
+        state.resize_registers(previous.registers.len());
+        state.continue_memory(previous);
+        match state.load_program(&content) {
+            Ok(instructions) => programs.push(instructions),
+            Err(error) => report_load_error(&error, &state.source, options),
+        }
+        states.push(state);
+        names.push(path.clone());
+    }
+    let max_steps = match options.max_steps.unwrap_or(DEFAULT_MAX_STEPS) {
+        0 => u64::MAX,
+        limit => limit,
+    };
+    let mut steps = 0;
+    let start = Instant::now();
+    let mut stdin = open_input(options);
+    let mut output = LimitedOutput::new(io::stdout(), options.limit_output);
+    let result = run_coop(&mut states, &programs, &mut stdin, &mut output, |program, state, pc, _| {
+        check_interrupted(state, pc);
+        if options.sandbox && steps == SANDBOX_MAX_STEPS {
+            report_error(&format!("step limit of {} reached in sandbox mode at line {} of `{}`", SANDBOX_MAX_STEPS, pc + 1, names[program]));
+        }
+        if steps == max_steps {
+            report_error(&format!("step limit of {} reached at line {} of `{}`, raise it with --max-steps", max_steps, pc + 1, names[program]));
+        }
+        if timed_out(start, options.timeout, steps) {
+            report_error(&format!("timeout of {:?} reached at line {} of `{}`", options.timeout.unwrap(), pc + 1, names[program]));
+        }
+        steps += 1;
+    });
+    if let Err((program, error)) = result {
+        if error_format() == ErrorFormat::Json || matches!(error, InterpretError::Quit { .. }) {
+            report_run_error(&error);
+        }
+        report_error(&format!("{} in `{}`", error, names[program]));
+    }
+    if !options.quiet {
+        for (state, name) in states.iter().zip(&names) {
+            println!("{}", finished_banner(Some(name)));
+            if !options.no_dump {
+                written(state.dump());
+            }
+        }
+    }
+    if let Some(code) = states.iter().find_map(|state| state.exit_code) {
+        std::process::exit(code.into());
+    }
+}
+
+/// Compares `state` to the state file at `path` and prints every difference, returning them.
+fn diff_state(state: &State, path: &str) -> Vec<String> {
+    let src = report_error_if_none(std::fs::read_to_string(path).ok(), &format!("unable to read state file `{}`", path));
+    let mut expected = State::new();
+    expected.resize_registers(state.registers.len());
+    expected.resize_memory(state.memory.len());
+    report_error_if_none(expected.load_json(&src), &format!("malformed state file `{}`", path));
+    let diff = state.diff(&expected);
+    if !diff.is_empty() {
+        println!("{} final state differs from `{}`", ansi_term::Color::Red.paint("Mismatch:"), path);
+        for line in &diff {
+            println!("{}", line);
+        }
+    }
+    diff
+}
+
+/// Sets registers, memory words and the stack pointer from the lines of the file at `path`, such as `r3 = 7`,
+/// `mem[10] = 42` and `sp = 100`. Empty lines and lines starting with `#` are skipped. Returns the registers that
+/// were set.
+fn init_state(state: &mut State, path: &str) -> Vec<usize> {
+    let src = report_error_if_none(std::fs::read_to_string(path).ok(), &format!("unable to read state file `{}`", path));
+    let mut registers = Vec::new();
+    for (index, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let malformed = || -> ! { report_error(&format!("malformed line {} in state file `{}`: `{}`", index + 1, path, line)) };
+        let (key, value) = line.split_once('=').unwrap_or_else(|| malformed());
+        let (key, value) = (key.trim().to_lowercase(), parse_value(value.trim()).unwrap_or_else(|| malformed()));
+        if key == "sp" {
+            let sp = usize::try_from(value).ok().and_then(|sp| state.set_initial_sp(sp));
+            report_error_if_none(sp, &format!("stack pointer {} on line {} of `{}` is beyond the end of memory", value, index + 1, path));
+        }
+        else if let Some(addr) = key.strip_prefix("mem[").and_then(|rest| rest.strip_suffix(']')) {
+            let addr = parse_value(addr.trim()).unwrap_or_else(|| malformed()) as usize;
+            if addr >= state.memory.len() {
+                report_error(&format!("address {} on line {} of `{}` is out of range", addr, index + 1, path));
+            }
+            state.memory[addr] = Wrapping(value);
+        }
+        else if let Some(reg) = key.strip_prefix('r').and_then(|reg| reg.parse::<usize>().ok()) {
+            if reg >= state.registers.len() {
+                report_error(&format!("r{} on line {} of `{}` does not exist", reg, index + 1, path));
+            }
+            state[&reg] = Wrapping(value);
+            registers.push(reg);
+        }
+        else {
+            malformed();
+        }
+    }
+    registers
 }
 
 fn main() {
-    let content = match get_source() {
+    let options = parse_args();
+    let content = match get_source(options.source.as_deref()) {
         Some(v) => v,
         None => report_error("No assembly file provided or unable to read file"),
     };
     let mut state = State::new();
-    for arg in std::env::args().skip(2).map(interpret_arg) {
-        match arg {
-            Ok((reg, val)) => state[&reg] = Wrapping(val),
-            Err(arg) => report_error(&format!("Unable to parse arg: `{}`", arg)),
-        }
-    }
-    let instruction: Vec<Instruction> = content.lines()
-        .enumerate()
-        .map(Instruction::parse(&mut state))
-        .collect();
-    let mut pc = 0;
-    while pc != instruction.len() {
-        pc = instruction[pc].apply(&mut state, pc);
-    }
-    println!("{}", ansi_term::Color::Green.paint("Finished:"));
-    state.dump();
+    state.config.soft_memory = options.soft_memory;
+    state.config.strict_overflow = options.strict_overflow;
+    state.config.dump_format = options.dump_format;
+    if let Some(radix) = options.dump_radix {
+        state.config.dump_radix = radix;
+    }
+    state.config.dump_memory = options.dump_memory;
+    state.config.sandbox = options.sandbox;
+    state.config.one_based_regs = options.one_based_regs;
+    state.config.strict_comments = options.strict_comments;
+    state.config.trap_addr = options.trap_addr;
+    state.config.case_insensitive_labels = options.case_insensitive;
+    state.config.debug_only = options.debug_only.clone();
+    if options.replay.is_none() && io::stdin().is_terminal() {
+        state.config.debug_prompt = Some(options.debug_prompt.clone().unwrap_or_else(|| DEFAULT_DEBUG_PROMPT.to_string()));
+    }
+    if let Some(max_line_len) = options.max_line_len {
+        state.config.max_line_len = max_line_len;
+    }
+    if let Some(width) = options.width {
+        state.config.width = width;
+    }
+    if let Some(count) = options.register_count {
+        state.resize_registers(count);
+    }
+    if let Some(words) = options.memory_words {
+        state.resize_memory(words);
+    }
+    if let Some(sp) = options.initial_sp {
+        report_error_if_none(state.set_initial_sp(sp), &format!("initial stack pointer {} is beyond the end of memory", sp));
+    }
+    if let Some(path) = &options.load_state {
+        let src = report_error_if_none(std::fs::read_to_string(path).ok(), &format!("unable to read state file `{}`", path));
+        report_error_if_none(state.load_json(&src), &format!("malformed state file `{}`", path));
+    }
+    let file_registers = options.init_state.as_ref().map(|path| init_state(&mut state, path)).unwrap_or_default();
+    for &(reg, val) in &options.registers {
+        if reg >= state.registers.len() {
+            report_error(&format!("r{} does not exist", reg));
+        }
+        state[&reg] = Wrapping(val);
+    }
+    let instruction = match state.load_program(&content) {
+        Ok(instructions) => instructions,
+        Err(error) => report_load_error(&error, &state.source, &options),
+    };
+    // The rest of the interpreter sees the program with its inline routines expanded, so lines match instructions.
+    let content = state.source.clone();
+    if options.warn_uninit && options.load_state.is_none() {
+        warn_uninit(&instruction, state.registers.len(), options.registers.iter().map(|&(reg, _)| reg).chain(file_registers.iter().copied()));
+    }
+    if options.warn_unreachable {
+        warn_unreachable(&state, &instruction);
+    }
+    if options.emit_resolved {
+        for ((index, instruction), line) in instruction.iter().enumerate().zip(content.lines()) {
+            let resolved = instruction.resolved(&state, index);
+            // Directives are needed to load the program the same way, so they are kept as written.
+            if is_directive(line, &state.config) {
+                println!("{}", line.trim());
+            }
+            else if options.disasm_hex {
+                println!("{:#}", resolved);
+            }
+            else {
+                println!("{}", resolved);
+            }
+        }
+        return;
+    }
+    if options.parse_only {
+        let width = instruction.len().saturating_sub(1).to_string().len();
+        for (index, instruction) in instruction.iter().enumerate() {
+            println!("{:>width$}  {:?}", index, instruction.resolved(&state, index));
+        }
+        println!("Labels:");
+        for (label, index) in state.sorted_labels() {
+            println!("{}: {}", label, index);
+        }
+        return;
+    }
+    let lines: Vec<&str> = content.lines().collect();
+    install_interrupt_handler();
+    if options.foreach {
+        foreach(&state, &instruction, &lines, &options);
+        return;
+    }
+    if options.coop {
+        coop(state, instruction, &options);
+        return;
+    }
+    let mut initialized = vec![options.load_state.is_some(); state.registers.len()];
+    for reg in options.registers.iter().map(|&(reg, _)| reg).chain(file_registers) {
+        initialized[reg] = true;
+    }
+    let mut stdin = open_input(&options);
+    execute(&mut state, &instruction, &lines, &options, initialized, &mut stdin);
+    let label = options.source.as_deref().filter(|_| options.label_output);
+    if let Some(path) = &options.save_state {
+        report_error_if_none(std::fs::write(path, state.dump_json(label) + "\n").ok(), &format!("unable to write state file `{}`", path));
+    }
+    if options.json {
+        if !options.quiet {
+            eprintln!("{}", finished_banner(label));
+        }
+        println!("{}", state.result_json(instruction.len(), label));
+    } else if !options.quiet {
+        println!("{}", finished_banner(label));
+        if !options.no_dump {
+            written(state.dump());
+        }
+    }
+    if options.stats {
+        print_stats(&state, &instruction);
+    }
+    let diff = options.diff_state.as_deref().map(|path| diff_state(&state, path)).unwrap_or_default();
+    if options.interactive {
+        // Quitting only leaves the prompt, the outcome of the run still decides the exit code.
+        match debug_prompt(&mut state, instruction.len(), true, &mut stdin, &mut std::io::stdout()) {
+            Ok(_) | Err(InterpretError::Quit { .. }) => (),
+            Err(error) => report_run_error(&error),
+        }
+    }
+    if !diff.is_empty() {
+        std::process::exit(1);
+    }
+    if let Some(code) = state.exit_code {
+        std::process::exit(code.into());
+    }
 }
@@ -0,0 +1,1241 @@
+use super::*;
+
+#[test]
+fn malformed_programs_are_errors() {
+    let lines = [
+        "add", "add r0", "add r0, r1", "inc r99", "inc x0", "j 1000", "shl r0", "shl r0, x",
+        "frobnicate r0", "\u{0}", "'", "j nowhere", "j -1",
+    ];
+    for line in lines {
+        match parse_program(line) {
+            Err(error) if error.line() == Some(1) => (),
+            result => panic!("`{}` parsed as {:?}", line, result.map(|(instructions, _)| instructions)),
+        }
+    }
+    let (instructions, labels) = parse_program("start:\ninc r0\njnz start").unwrap();
+    assert_eq!((instructions.len(), labels["start"]), (3, 0));
+}
+
+#[test]
+fn the_hook_sees_every_step_before_it_runs() {
+    let mut state = State::new();
+    state[&0] = Wrapping(2);
+    let instructions = state.load_program("l:\ndec r0\njnz l\ninc r1").unwrap();
+    let mut seen = Vec::new();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |state, pc, _| seen.push((pc, state[&0].0, state.steps))).unwrap();
+    assert_eq!(seen, [(0, 2, 0), (1, 2, 1), (2, 1, 2), (0, 1, 3), (1, 1, 4), (2, 0, 5), (3, 0, 6)]);
+    assert_eq!((state.steps, state[&1]), (7, Wrapping(1)));
+}
+
+#[test]
+fn labels_are_sorted_by_line_and_then_by_name() {
+    // Every `State` hashes its labels with a new seed, so repeating catches any dependence on the map order.
+    for _ in 0..10 {
+        let mut state = State::new();
+        state.load_program("zeta:\ninc r0\nbeta:\nalpha:\ninc r0\nmid:").unwrap();
+        state.labels.insert("also_beta".to_string(), 2);
+        assert_eq!(state.sorted_labels(), [("zeta", 0), ("also_beta", 2), ("beta", 2), ("alpha", 3), ("mid", 5)]);
+    }
+}
+
+#[test]
+fn rotates_take_the_count_from_a_register() {
+    let value = 0x8000_0000_0000_0001;
+    for (count, left, right) in [(0, value, value), (1, 0x3, 0xC000_0000_0000_0000), (64, value, value), (65, 0x3, 0xC000_0000_0000_0000)] {
+        let mut state = State::new();
+        state.registers[..3].copy_from_slice(&[value, value, count].map(Wrapping));
+        Instruction::Rolr { reg: 0, by: 2 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+        Instruction::Rorr { reg: 1, by: 2 }.apply(&mut state, 1, &mut &b""[..], &mut Vec::new()).unwrap();
+        assert_eq!(state.registers[..3], [left, right, count].map(Wrapping), "count {}", count);
+    }
+}
+
+#[test]
+fn split_and_join_convert_between_a_register_and_its_halves() {
+    let mut state = State::new();
+    state[&0] = Wrapping(0x1234_5678_0000_0000);
+    Instruction::Split { hi: 1, lo: 2, src: 0 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&1], state[&2], state.zero), (Wrapping(0x1234_5678), Wrapping(0), true));
+    Instruction::Join { to: 3, hi: 2, lo: 1 }.apply(&mut state, 1, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&3], state.zero), (Wrapping(0x1234_5678), false));
+    state.registers[1..3].copy_from_slice(&[0xFFFF_FFFF_0000_0001, 0xFFFF_FFFF_0000_0002].map(Wrapping));
+    Instruction::Join { to: 0, hi: 1, lo: 2 }.apply(&mut state, 2, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!(state[&0], Wrapping(0x1_0000_0002));
+    let mut state = State::new();
+    state.config.width = 8;
+    state.registers[..2].copy_from_slice(&[0xAB, 0x1F].map(Wrapping));
+    Instruction::Split { hi: 2, lo: 3, src: 0 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    Instruction::Join { to: 4, hi: 1, lo: 0 }.apply(&mut state, 1, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!(state.registers[2..5], [0xA, 0xB, 0xFB].map(Wrapping));
+}
+
+#[test]
+fn sign_and_zero_extension_take_the_low_bits() {
+    let cases = [
+        (64, 0x80, [0xFFFF_FFFF_FFFF_FF80, 0x80, 0x80, 0x80, 0x80, 0x80]),
+        (16, 0x8080, [0xFF80, 0x8080, 0x8080, 0x80, 0x8080, 0x8080]),
+        (8, 0x80, [0x80; 6]),
+    ];
+    for (width, value, extended) in cases {
+        let mut state = State::new();
+        state.config.width = width;
+        let instructions = state.load_program("sxtb r0\nsxtw r1\nsxtl r2\nzxtb r3\nzxtw r4\nzxtl r5").unwrap();
+        state.registers[..6].fill(Wrapping(value));
+        run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+        assert_eq!(state.registers[..6], extended.map(Wrapping), "width {}", width);
+    }
+}
+
+#[test]
+fn clamp_limits_a_register_to_a_range() {
+    for (value, lo, hi, clamped) in [(1, 3, 7, 3), (5, 3, 7, 5), (9, 3, 7, 7), (4, 0, 0, 0), (4, 4, 4, 4)] {
+        let mut state = State::new();
+        state.registers[..3].copy_from_slice(&[value, lo, hi].map(Wrapping));
+        Instruction::Clamp { reg: 0, lo: 1, hi: 2 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+        assert_eq!((state[&0], state.zero), (Wrapping(clamped), clamped == 0), "{} in {}..={}", value, lo, hi);
+    }
+}
+
+#[test]
+fn data_directives_fill_memory_in_order() {
+    let mut state = State::new();
+    state.load_program("bytes: .byte 7, 0xFF\ninc r0\ntext: .ascii \"a; \\\"b\\\"\\n\" // note\n.byte 1").unwrap();
+    assert_eq!(state.memory[..9], [7, 0xFF, b'a', b';', b' ', b'"', b'b', b'"', b'\n'].map(|byte| Wrapping(byte as u64)));
+    assert_eq!(state.memory[9..11], [Wrapping(1), Wrapping(0)]);
+    assert_eq!((state.data_labels["bytes"], state.data_labels["text"]), (0, 2));
+    assert!(state.labels.is_empty());
+    for source in [".byte 256", ".ascii \"open", ".ascii \"a\" b"] {
+        assert!(parse_program(source).is_err(), "`{}` parsed", source);
+    }
+}
+
+#[test]
+fn incm_and_decm_count_in_memory() {
+    let mut state = State::new();
+    let instructions = state.load_program(".byte 3\nl:\nincm r1\ndecm r0\njnz l").unwrap();
+    state[&1] = Wrapping(5);
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!((state.memory[0], state.memory[5], state.zero), (Wrapping(0), Wrapping(3), true));
+    assert_eq!(state.registers[..2], [Wrapping(0), Wrapping(5)]);
+    state.memory[0] = Wrapping(u64::MAX);
+    Instruction::Incm { addr: 0 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state.memory[0], state.zero), (Wrapping(0), true));
+}
+
+#[test]
+fn checksum_hashes_the_other_registers() {
+    let mut state = State::new();
+    Instruction::Checksum { reg: 7 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!(state[&7], Wrapping(0x8AC1_23D6_F7DC_E585));
+    state.registers = [99, 0, 1, 0, 0, 0, 0, 0].map(Wrapping).to_vec();
+    Instruction::Checksum { reg: 0 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    // FNV-1a over the bytes of r1 to r7, where only the lowest byte of r2 is set.
+    let bytes = (1..8).flat_map(|reg| [(reg == 2) as u8, 0, 0, 0, 0, 0, 0, 0]);
+    let expected = bytes.fold(0xCBF2_9CE4_8422_2325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01B3));
+    assert_eq!((state[&0], state.zero), (Wrapping(expected), false));
+    assert_eq!(state.checksum(0), expected);
+}
+
+#[test]
+fn predicates_skip_instructions_when_the_condition_fails() {
+    let mut state = State::new();
+    let instructions = state.load_program("zf: inc r1\nnzf: inc r2\nnzf: j end\ninc r0\nend:\nl: zf: inc r3\nnzf: inc r4").unwrap();
+    assert!(matches!(&instructions[0], Instruction::Predicated { predicate: Predicate::Zero, inner } if matches!(**inner, Instruction::Inc { reg: 1 })));
+    state.zero = true;
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[..5], [0, 1, 1, 0, 1].map(Wrapping));
+    for source in ["zf:", "zf: nzf: inc r0", "zf: l: inc r0"] {
+        assert!(parse_program(source).is_err(), "`{}` parsed", source);
+    }
+}
+
+#[test]
+fn proglen_counts_every_line_and_is_the_end_target() {
+    let mut state = State::new();
+    let instructions = state.load_program("proglen r0\n\n// comment\n.byte 1\nj 6\ninc r1").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!((state.program_len, state[&0], state[&1]), (6, Wrapping(6), Wrapping(0)));
+}
+
+#[test]
+fn sumregs_and_xorregs_reduce_every_register() {
+    let mut state = State::new();
+    state.registers = [1, 2, 0, 4, 0, 0, 0, u64::MAX].map(Wrapping).to_vec();
+    Instruction::Sumregs { reg: 0 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&0], state.zero), (Wrapping(6), false));
+    state.registers = [0b011, 0b110, 0b101, 0, 0, 0, 0, 0].map(Wrapping).to_vec();
+    Instruction::Xorregs { reg: 1 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&1], state.zero), (Wrapping(0), true));
+}
+
+#[test]
+fn the_stack_grows_down_within_its_declared_size() {
+    let mut state = State::new();
+    let instructions = state.load_program(".stack 4\npush r0\npush r1\npush r2\npop r3\npush r2\npush r1\npop r4").unwrap();
+    state.registers[..3].copy_from_slice(&[1, 2, 3].map(Wrapping));
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!((state[&3], state[&4], state.sp), (Wrapping(3), Wrapping(2), MEMORY_WORDS - 3));
+    assert_eq!(state.memory[MEMORY_WORDS - 3..], [3, 2, 1].map(Wrapping));
+    assert_eq!(state.stack_limit(), MEMORY_WORDS - 4);
+    for source in [".byte 1, 2\n.stack 1023", ".stack 4\n.stack 4", ".stack 1025", ".stack"] {
+        assert!(parse_program(source).is_err(), "`{}` parsed", source);
+    }
+}
+
+#[test]
+fn conditional_calls_only_save_a_return_address_when_taken() {
+    let depths = |r0: u64| {
+        let mut state = State::new();
+        state[&0] = Wrapping(r0);
+        let instructions = state.load_program("dec r0\ncallnz sub\ncallz sub\nj end\nsub:\nret\nend:").unwrap();
+        let mut depths = Vec::new();
+        run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |state, pc, _| depths.push((pc, state.call_stack.clone()))).unwrap();
+        depths
+    };
+    assert_eq!(depths(1), [(0, vec![]), (1, vec![]), (2, vec![]), (4, vec![3]), (5, vec![3]), (3, vec![]), (6, vec![])]);
+    assert_eq!(depths(2), [(0, vec![]), (1, vec![]), (4, vec![2]), (5, vec![2]), (2, vec![]), (3, vec![]), (6, vec![])]);
+}
+
+#[test]
+fn operands_are_split_on_commas_and_must_not_be_empty() {
+    for source in ["add r0,, r1", "add r0, r1, r2,", "add ,r0, r1, r2", "push r0,", "add r0 r1, r2"] {
+        match parse_program(source) {
+            Err(InterpretError::Parse { line: 1, .. }) => (),
+            result => panic!("`{}` parsed as {:?}", source, result.map(|(instructions, _)| instructions)),
+        }
+    }
+    let (instructions, _) = parse_program("add r2 ,r0,  r1").unwrap();
+    assert!(matches!(instructions[..], [Instruction::Add { to: 2, op1: 0, op2: Operand::Reg(1) }]));
+}
+
+#[test]
+fn rothalf_swaps_the_halves() {
+    let mut state = State::new();
+    state[&0] = Wrapping(0x0000_0001_FFFF_0000);
+    Instruction::Rothalf { reg: 0 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&0], state.zero), (Wrapping(0xFFFF_0000_0000_0001), false));
+    Instruction::Rothalf { reg: 1 }.apply(&mut state, 1, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&1], state.zero), (Wrapping(0), true));
+    state.config.width = 8;
+    state[&0] = Wrapping(0x12);
+    Instruction::Rothalf { reg: 0 }.apply(&mut state, 2, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!(state[&0], Wrapping(0x21));
+}
+
+#[test]
+fn time_reads_the_clock_except_in_sandbox_mode() {
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let mut state = State::new();
+    state[&0] = Wrapping(7);
+    Instruction::Time { reg: 0 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    assert!((before..=after).contains(&state[&0].0));
+    state.config.sandbox = true;
+    Instruction::Time { reg: 0 }.apply(&mut state, 1, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&0], state.zero), (Wrapping(SANDBOX_TIME), true));
+}
+
+#[test]
+fn lines_longer_than_the_limit_are_rejected() {
+    let mut state = State::new();
+    state.config.max_line_len = 10;
+    assert!(state.load_program("inc r0\ninc r0 //x").is_ok());
+    let mut state = State::new();
+    state.config.max_line_len = 10;
+    match state.load_program("inc r0\n// 1234567890\ninc r0") {
+        Err(InterpretError::Parse { line: 2, message, .. }) => assert_eq!(message, "line 2 is longer than 10 bytes"),
+        result => panic!("loaded as {:?}", result.map(|instructions| instructions.len())),
+    }
+    let long_comment = format!("inc r0 // {}", "x".repeat(DEFAULT_MAX_LINE_LEN));
+    assert!(parse_program(&long_comment).is_err());
+    assert!(parse_program(&long_comment[..DEFAULT_MAX_LINE_LEN]).is_ok());
+}
+
+#[test]
+fn jempty_loops_until_the_stack_is_drained() {
+    let mut state = State::new();
+    let instructions = state.load_program("push r0\npush r0\npush r0\nl:\njempty end\npop r1\ninc r2\njnempty l\nend:").unwrap();
+    let mut loops = 0;
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, pc, _| loops += (pc == 3) as usize).unwrap();
+    assert_eq!((state[&2], state.sp, loops), (Wrapping(3), MEMORY_WORDS, 3));
+    let mut state = State::new();
+    let instructions = state.load_program("jempty end\ninc r0\nend:").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state[&0], Wrapping(0));
+}
+
+#[test]
+fn countif_counts_equal_operands() {
+    let mut state = State::new();
+    state.registers[..4].copy_from_slice(&[u64::MAX, 4, 4, 5].map(Wrapping));
+    Instruction::Countif { acc: 0, a: 1, b: 2 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&0], state.zero), (Wrapping(0), true));
+    state[&0] = Wrapping(3);
+    Instruction::Countif { acc: 0, a: 1, b: 3 }.apply(&mut state, 1, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&0], state.zero), (Wrapping(3), false));
+    Instruction::Countif { acc: 0, a: 3, b: 3 }.apply(&mut state, 2, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!(state[&0], Wrapping(4));
+}
+
+#[test]
+fn printed_output_goes_to_the_given_writer() {
+    let mut state = State::new();
+    state.config.sandbox = true;
+    let instructions = state.load_program("inc r0\ninc r0\ndebug").unwrap();
+    let mut output = Vec::new();
+    run(&mut state, &instructions, &mut &b""[..], &mut output, |_, _, _| ()).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("line 3") && output.contains("Zero: false"), "{}", output);
+    assert!(output.lines().any(|line| line.starts_with("R0:") && line.contains(" 2 ")), "{}", output);
+}
+
+#[test]
+fn debug_commands_come_from_the_given_reader() {
+    let mut state = State::new();
+    let instructions = state.load_program("inc r0\ndebug\ninc r0").unwrap();
+    let mut output = Vec::new();
+    run(&mut state, &instructions, &mut &b"set r4 7\ndump\n\nignored\n"[..], &mut output, |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[..5], [2, 0, 0, 0, 7].map(Wrapping));
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.lines().any(|line| line.starts_with("R4:") && line.contains(" 7 ")), "{}", output);
+}
+
+#[test]
+fn modexp_computes_a_modular_power() {
+    let mut state = State::new();
+    state.registers[1..4].copy_from_slice(&[4, 13, 497].map(Wrapping));
+    Instruction::Modexp { to: 0, base: 1, exp: 2, modulus: 3 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!(state[&0], Wrapping(445));
+    state.registers[1..4].copy_from_slice(&[u64::MAX - 1, u64::MAX, u64::MAX].map(Wrapping));
+    Instruction::Modexp { to: 0, base: 1, exp: 2, modulus: 3 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!(state[&0], Wrapping(u64::MAX - 1));
+    state.registers[1..4].copy_from_slice(&[5, 0, 1].map(Wrapping));
+    Instruction::Modexp { to: 0, base: 1, exp: 2, modulus: 3 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&0], state.zero), (Wrapping(0), true));
+}
+
+#[test]
+fn errors_point_at_the_offending_token() {
+    let source = "inc r0\nadd r0, r9, r1";
+    let error = parse_program(source).unwrap_err();
+    assert_eq!(error.annotate(source).unwrap(), "2 | add r0, r9, r1\n  |         ^^");
+    let source = "inc r0, r1";
+    assert_eq!(parse_program(source).unwrap_err().annotate(source).unwrap(), "1 | inc r0, r1\n  |         ^^");
+    let source = "j 1000";
+    assert_eq!(parse_program(source).unwrap_err().annotate(source).unwrap(), "1 | j 1000\n  |   ^^^^");
+    assert_eq!(parse_program("add r0").unwrap_err().annotate("add r0"), None);
+    assert_eq!(InterpretError::UnknownLabel { line: 0, name: "l".to_string() }.annotate("j l"), None);
+}
+
+#[test]
+fn the_last_operand_of_arithmetic_can_be_an_immediate() {
+    let mut state = State::new();
+    let instructions = state.load_program("add r1, r0, #300 # comment\nand r2, r1, 0xff\nsub r3, r2, 44\nxor r4, r3, r3").unwrap();
+    assert!(matches!(instructions[1], Instruction::And { to: 2, op1: 1, op2: Operand::Imm(Wrapping(0xff)) }));
+    assert!(matches!(instructions[3], Instruction::Xor { to: 4, op1: 3, op2: Operand::Reg(3) }));
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[1..5], [300, 44, 0, 0].map(Wrapping));
+    assert!(state.zero);
+    assert!(parse_program("add r0, r1, #x").is_err());
+}
+
+#[test]
+fn movi_loads_an_immediate_without_touching_the_zero_flag() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, -1\nmovi r1, 0x10\nmovi r2, 0").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[..3], [u64::MAX, 16, 0].map(Wrapping));
+    assert!(!state.zero);
+    assert!(parse_program("movi r0, -x").is_err());
+}
+
+#[test]
+fn movi_with_an_alias_copies_the_register() {
+    let source = ".alias counter r3
+movi r1, counter
+movi r2, data
+data: .byte 1";
+    let (instructions, _) = parse_program(source).unwrap();
+    assert!(matches!(instructions[1], Instruction::Mov { to: 1, from: 3 }));
+    assert!(matches!(&instructions[2], Instruction::Lea { reg: 2, label } if label == "data"));
+    assert_eq!(run_source(source, &[(3, 9)]).unwrap().registers[1], Wrapping(9));
+}
+
+#[test]
+fn mulsat_clamps_an_overflowing_product() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, 0x10000000000\nmovi r1, 0x40000000\nmovi r4, 3\nmulsat r2, r0, r1\nmulsat r3, r0, r4\nmulsat r5, r0, r6").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[2..6], [u64::MAX, 3 << 40, 3, 0].map(Wrapping));
+    assert!(state.zero);
+}
+
+#[test]
+fn one_based_registers_relabel_the_dump() {
+    let mut state = State::new();
+    state[&0] = Wrapping(3);
+    state.config.one_based_regs = true;
+    let mut table = Vec::new();
+    state.write_dump(&mut table).unwrap();
+    let table = String::from_utf8(table).unwrap();
+    let rows: Vec<&str> = table.lines().collect();
+    assert!(rows[3].starts_with("R1:") && rows[3].ends_with("0x0000000000000003"), "{}", table);
+    assert!(rows[10].starts_with("R8:"), "{}", table);
+    assert!(!table.contains("R0"));
+    let mut compact = Vec::new();
+    state.write_dump_compact(&mut compact).unwrap();
+    assert!(String::from_utf8(compact).unwrap().ends_with(" R1=3 R2=0 R3=0 R4=0 R5=0 R6=0 R7=0 R8=0\n"));
+}
+
+#[test]
+fn mul_can_keep_the_high_half_of_the_product() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, -1\nmovi r1, 3\nmul r2, r0, r1\nmul r3, r4, r0, r1\nmul r5, r6, r7, r0").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[2..5], [u64::MAX - 2, 2, u64::MAX - 2].map(Wrapping));
+    assert!(state.zero);
+    assert_eq!(instructions[3].to_string(), "mul     r3, r4, r0, r1");
+}
+
+#[test]
+fn only_the_selected_debug_points_stop() {
+    let source = "debug first\ninc r0\ndebug second\ninc r0\ndebug";
+    // Every debug point that stops takes one `set` command from the input before continuing.
+    let input = b"set r1 1\n\nset r2 1\n\nset r3 1\n\n";
+    for (tags, stopped) in [(None, [1, 1, 1]), (Some(vec!["second"]), [1, 0, 0]), (Some(vec!["first", "second"]), [1, 1, 0])] {
+        let mut state = State::new();
+        state.config.debug_only = tags.map(|tags| tags.into_iter().map(String::from).collect());
+        let instructions = state.load_program(source).unwrap();
+        let mut output = Vec::new();
+        run(&mut state, &instructions, &mut &input[..], &mut output, |_, _, _| ()).unwrap();
+        assert_eq!(state.registers[1..4], stopped.map(Wrapping));
+        let stops = stopped.iter().sum::<u64>() as usize;
+        assert_eq!(String::from_utf8(output).unwrap().matches("Debug:").count(), stops);
+    }
+}
+
+#[test]
+fn signed_division_rounds_towards_zero() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, -7\nmovi r1, 2\ndiv r2, r1, r1\nmod r3, r1, r1\nidiv r4, r0, r1\nimod r5, r0, r1\nmovi r0, 0x8000000000000000\nmovi r1, -1\nidiv r6, r0, r1\nimod r7, r0, r1").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[2..8], [1, 0, -3i64 as u64, -1i64 as u64, i64::MAX as u64, 0].map(Wrapping));
+    assert!(state.zero);
+}
+
+#[test]
+fn rbit_reverses_the_bits() {
+    let mut state = State::new();
+    state.registers[..3].copy_from_slice(&[1, 0xF0, 0].map(Wrapping));
+    for reg in 0..3 {
+        Instruction::Rbit { reg }.apply(&mut state, reg, &mut &b""[..], &mut Vec::new()).unwrap();
+    }
+    assert_eq!(state.registers[..3], [0x8000_0000_0000_0000, 0x0F00_0000_0000_0000, 0].map(Wrapping));
+    assert!(state.zero);
+    state.config.width = 8;
+    state.registers[..2].copy_from_slice(&[1, 0xF0].map(Wrapping));
+    for reg in 0..2 {
+        Instruction::Rbit { reg }.apply(&mut state, reg, &mut &b""[..], &mut Vec::new()).unwrap();
+    }
+    assert_eq!(state.registers[..2], [0x80, 0x0F].map(Wrapping));
+}
+
+#[test]
+fn the_stack_starts_at_the_end_of_resized_memory() {
+    let mut state = State::new();
+    state.resize_memory(4);
+    let instructions = state.load_program(".byte 7\nmovi r0, 9\npush r0").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.memory, [7, 0, 0, 9].map(Wrapping));
+    assert_eq!(state.sp, 3);
+}
+
+#[test]
+fn the_stack_can_start_below_the_end_of_memory() {
+    let mut state = State::new();
+    assert_eq!(state.set_initial_sp(MEMORY_WORDS + 1), None);
+    state.set_initial_sp(512).unwrap();
+    let instructions = state.load_program("movi r0, 9\npush r0\ninc r1\npop r2\njempty end\ninc r3\nend:").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |state, pc, _| if pc == 2 {
+        assert_eq!((state.memory[511], state.sp), (Wrapping(9), 511));
+    }).unwrap();
+    assert_eq!(state.sp, 512);
+    assert_eq!(state.registers[1..4], [1, 9, 0].map(Wrapping));
+    let mut dump = Vec::new();
+    state.write_dump(&mut dump).unwrap();
+    assert!(String::from_utf8(dump).unwrap().contains("SP: 512\n"));
+}
+
+#[test]
+fn set_instructions_compare_with_an_immediate() {
+    let instructions = parse_program("seteqi r1, r0, 5\nsetlti r2, r0, 5\nsetgti r3, r0, 0x5").unwrap().0;
+    for (value, expected) in [(5, [1, 0, 0]), (4, [0, 1, 0]), (6, [0, 0, 1]), (u64::MAX, [0, 0, 1])] {
+        let mut state = State::new();
+        state[&0] = Wrapping(value);
+        run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+        assert_eq!(state.registers[1..4], expected.map(Wrapping), "{}", value);
+        assert_eq!(state.zero, expected[2] == 0);
+    }
+}
+
+#[test]
+fn wrapping_arithmetic_sets_the_carry_flag() {
+    let mut state = State::new();
+    let instructions = state.load_program("dec r0\njnc wrong\nmovi r1, 1\nadd r2, r0, r1\njnc wrong\nsub r3, r1, 1\njc wrong\ninc r4\nj end\nwrong:\nmovi r5, 1\nend:").unwrap();
+    let mut carries = Vec::new();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |state, pc, _| carries.push((pc, state.carry))).unwrap();
+    assert_eq!(state.registers[..6], [u64::MAX, 1, 0, 0, 1, 0].map(Wrapping));
+    assert_eq!(carries[1..], [(1, true), (2, true), (3, true), (4, true), (5, true), (6, false), (7, false), (8, false), (11, false)]);
+}
+
+#[test]
+fn skipz_skips_the_next_instruction_when_zero() {
+    let mut state = State::new();
+    let instructions = state.load_program("inc r0\ndec r0\nskipz\ninc r1\nskipnz\ninc r2\ninc r3\nskipnz\ninc r4").unwrap();
+    let mut visited = Vec::new();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, pc, _| visited.push(pc)).unwrap();
+    assert_eq!(state.registers[1..5], [0, 1, 1, 0].map(Wrapping));
+    assert_eq!(visited, [0, 1, 2, 4, 5, 6, 7]);
+}
+
+#[test]
+fn signed_jumps_follow_cmp() {
+    let jumps = ["jg", "jl", "jge", "jle"].map(|op| parse_program(&format!("{} l\nl:", op)).unwrap().0.remove(0));
+    for (a, b, taken) in [(1, 2, [false, true, false, true]), (-1, 1, [false, true, false, true]), (i64::MIN, 1, [false, true, false, true]), (i64::MAX, -1, [true, false, true, false]), (3, 3, [false, false, true, true])] {
+        let mut state = State::new();
+        state.registers[..2].copy_from_slice(&[a as u64, b as u64].map(Wrapping));
+        Instruction::Cmp { op1: 0, op2: 1 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+        assert_eq!(jumps.each_ref().map(|jump| state.signed_condition(jump)), taken, "{} {}", a, b);
+        assert_eq!((state.zero, state.carry), (a == b, (a as u64) < b as u64));
+        assert_eq!(state.overflow, a.checked_sub(b).is_none());
+    }
+}
+
+#[test]
+fn errors_have_structured_variants() {
+    match parse_program("inc r0\njnz mising\nmissing:") {
+        Err(InterpretError::UnknownLabel { line, name }) => assert_eq!((line, name.as_str()), (2, "mising")),
+        result => panic!("parsed as {:?}", result.map(|(instructions, _)| instructions)),
+    }
+    let errors = ["inc r9", "frobnicate r0", "j 5"].map(|source| parse_program(source).unwrap_err());
+    assert!(matches!(errors[0], InterpretError::UnknownRegister { line: 1, reg: 9 }));
+    assert!(matches!(&errors[1], InterpretError::GarbageInstruction { line: 1, text } if text == "frobnicate"));
+    assert!(matches!(errors[2], InterpretError::JumpOutOfRange { line: 1, target: 5 }));
+    assert_eq!(errors.each_ref().map(|error| error.to_string()), ["r9 does not exist", "garbage instruction `frobnicate`", "jump target 5 out of range on line 1"]);
+    let error: Box<dyn std::error::Error> = Box::new(parse_program("j nowhere").unwrap_err());
+    assert_eq!(error.to_string(), "unknown label `nowhere` on line 1");
+}
+
+#[test]
+fn neg_is_the_twos_complement() {
+    let mut state = State::new();
+    state.registers[..3].copy_from_slice(&[5, 1 << 63, 0].map(Wrapping));
+    for reg in 0..3 {
+        Instruction::Neg { reg }.apply(&mut state, reg, &mut &b""[..], &mut Vec::new()).unwrap();
+    }
+    assert_eq!(state.registers[..3], [-5i64 as u64, 1 << 63, 0].map(Wrapping));
+    assert!(state.zero);
+}
+
+#[test]
+fn paddw_wraps_each_lane_on_its_own() {
+    let mut state = State::new();
+    state.registers[..2].copy_from_slice(&[0x0001_FFFF_7FFF_FFFF, 0x0001_0001_0001_0002].map(Wrapping));
+    Instruction::Paddw { to: 2, op1: 0, op2: 1 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!(state[&2], Wrapping(0x0002_0000_8000_0001));
+    state.registers[..2].copy_from_slice(&[0xFFFF_0000_FFFF_0000, 0x0001_0000_0001_0000].map(Wrapping));
+    Instruction::Paddw { to: 2, op1: 0, op2: 1 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&2], state.zero), (Wrapping(0), true));
+}
+
+#[test]
+fn rotations_by_an_immediate_wrap_the_amount() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, 0x8000000000000001\nmov r1, r0\nmov r2, r0\nrol r0, 1\nror r1, 68\nrol r2, 64").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[..3], [3, 0x1800_0000_0000_0000, 0x8000_0000_0000_0001].map(Wrapping));
+}
+
+#[test]
+fn a_snapshot_restores_the_whole_machine() {
+    let instructions = parse_program("movi r0, 7\npush r0\ndebug\ninc r3").unwrap().0;
+    let session = |input: &[u8]| {
+        let mut state = State::new();
+        let mut output = Vec::new();
+        run(&mut state, &instructions, &mut &input[..], &mut output, |_, _, _| ()).unwrap();
+        (state.snapshot(), String::from_utf8(output).unwrap())
+    };
+    let (untouched, _) = session(b"\n");
+    let (restored, output) = session(b"save s\nmovi r0, 1\npop r2\nstore r0, r0\ndec r0\nrestore s\n\n");
+    assert_eq!(restored, untouched);
+    assert!(output.contains(" R0=7 R1=0 R2=0 R3=0 "), "{}", output);
+    let (changed, _) = session(b"save s\nmovi r0, 1\npop r2\nstore r0, r0\ndec r0\n\n");
+    assert_ne!(changed, untouched);
+}
+
+#[test]
+fn shifts_by_64_or_more_clear_the_register() {
+    for (amount, left, right) in [(0, 0b101, 0b101), (63, 1 << 63, 0), (64, 0, 0), (1 << 32, 0, 0), (u64::MAX, 0, 0)] {
+        let mut state = State::new();
+        state.registers[..2].copy_from_slice(&[0b101, 0b101].map(Wrapping));
+        Instruction::Shl { reg: 0, amount: Operand::Imm(Wrapping(amount)) }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+        Instruction::Shr { reg: 1, amount: Operand::Imm(Wrapping(amount)) }.apply(&mut state, 1, &mut &b""[..], &mut Vec::new()).unwrap();
+        assert_eq!(state.registers[..2], [left, right].map(Wrapping), "amount {}", amount);
+    }
+}
+
+#[test]
+fn parityof_tells_odd_from_even_popcounts() {
+    let mut state = State::new();
+    state.registers[..3].copy_from_slice(&[0b1011, 0b1001, u64::MAX].map(Wrapping));
+    for (to, from) in [(3, 0), (4, 1), (5, 2)] {
+        Instruction::Parityof { to, from }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    }
+    assert_eq!(state.registers[3..6], [1, 0, 0].map(Wrapping));
+    assert!(state.zero);
+}
+
+#[test]
+fn shifts_and_rotations_can_take_the_amount_from_a_register() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, 1\nmov r1, r0\nmov r2, r0\nmovi r4, 3\nmovi r5, 65\nshl r0, r4\nshl r1, r5\nror r2, r4").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[..3], [8, 0, 1 << 61].map(Wrapping));
+    assert!(matches!(instructions[7], Instruction::Ror { reg: 2, amount: Operand::Reg(4) }));
+}
+
+#[test]
+fn jump_targets_can_have_an_offset() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r2, 2\nj skip+2\nskip:\ninc r0\ninc r1\ndec r2\njnz skip + 2").unwrap();
+    let mut visited = Vec::new();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, pc, _| visited.push(pc)).unwrap();
+    assert_eq!(visited, [0, 1, 4, 5, 6, 4, 5, 6]);
+    assert_eq!(state.registers[..2], [0, 2].map(Wrapping));
+    assert!(matches!(parse_program("j skip+3\nskip:"), Err(InterpretError::JumpOutOfRange { line: 1, target: 4 })));
+    assert!(matches!(parse_program("inc r0\nskip: j skip-2"), Err(InterpretError::JumpOutOfRange { line: 2, target: -1 })));
+    for source in ["j 9223372036854775807+1", "j 5+9223372036854775807"] {
+        assert!(matches!(parse_program(source), Err(InterpretError::UnknownLabel { line: 1, .. })), "{}", source);
+    }
+}
+
+#[test]
+fn immediates_can_be_written_in_any_base_or_as_a_character() {
+    let values = ["0x1F", "0b101", "0o17", "-2", "'A'", "'a'", "'\\n'", "'\\''", "' '"].map(parse_value);
+    assert_eq!(values, [31, 5, 15, u64::MAX - 1, 65, 97, 10, 39, 32].map(Some));
+    assert_eq!(["0b2", "''", "'ab'", "0x"].map(parse_value), [None; 4]);
+    let mut state = State::new();
+    let instructions = state.load_program("MOVI R0, 'A'\nmovi r1, 'a'\nadd r2, r1, 0B1").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[..3], [65, 97, 98].map(Wrapping));
+}
+
+#[test]
+fn out_and_putc_print_to_the_output() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, -1\nout r0\nmovi r1, 0x168\nputc r1\nmovi r1, 'i'\nputc r1\nout r2").unwrap();
+    let mut output = Vec::new();
+    run(&mut state, &instructions, &mut &b""[..], &mut output, |_, _, _| ()).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "18446744073709551615\nhi0\n");
+}
+
+#[test]
+fn loadd_and_stored_address_a_field_from_a_base() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, 100\nmovi r1, 9\nstored r1, r0, 2\nloadd r2, r0, 2\ninc r1\nstored r1, r0, -1\nloadd r3, r0, -1").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.memory[99..103], [10, 0, 0, 9].map(Wrapping));
+    assert_eq!(state.registers[2..4], [9, 10].map(Wrapping));
+}
+
+#[test]
+fn in_reads_values_from_the_input() {
+    let instructions = parse_program("in r0\nin r1\nin r2\nin r3").unwrap().0;
+    let mut state = State::new();
+    run(&mut state, &instructions, &mut &b"5\n0x10\n'A'\n"[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[..4], [5, 16, 65, 0].map(Wrapping));
+    assert!(state.zero);
+    let mut state = State::new();
+    state.config.sandbox = true;
+    let result = run(&mut state, &instructions, &mut &b"5\n6\n7\n8\n"[..], &mut Vec::new(), |_, _, _| ());
+    assert!(matches!(result, Err(InterpretError::Runtime { line: 1, message }) if message == "`in` cannot read input in sandbox mode on line 1"));
+    assert_eq!(state.registers[0], Wrapping(0));
+}
+
+#[test]
+fn labels_differing_in_case_are_distinct() {
+    let mut state = State::new();
+    let instructions = state.load_program("J End\nend:\nmovi r0, 1\nj done\nEnd:\nMOVI R1, 1\ndone:").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[..2], [0, 1].map(Wrapping));
+    assert_eq!((state.labels["end"], state.labels["End"]), (1, 4));
+    assert!(matches!(parse_program("j END\nend:"), Err(InterpretError::UnknownLabel { line: 1, name }) if name == "END"));
+}
+
+#[test]
+fn memset_fills_a_block() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, 10\nmovi r1, 0xAB\nmovi r2, 3\nmemset r0, r1, r2\nzero r1\nmovi r0, 11\nmovi r2, 1\nmemset r0, r1, r2").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.memory[9..14], [0, 0xAB, 0, 0xAB, 0].map(Wrapping));
+    let mut state = State::new();
+    state.config.soft_memory = true;
+    state.fill(Wrapping(MEMORY_WORDS as u64 - 2), Wrapping(5), u64::MAX, 0).unwrap();
+    assert_eq!(state.memory[MEMORY_WORDS - 3..], [0, 5, 5].map(Wrapping));
+}
+
+#[test]
+fn an_instruction_can_follow_a_label_on_its_line() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, 3\nloop:inc r1\nback: dec r0\njnz loop").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(instructions.len(), 4);
+    assert_eq!((state.labels["loop"], state.labels["back"]), (1, 2));
+    assert_eq!(state.registers[..2], [0, 3].map(Wrapping));
+}
+
+#[test]
+fn the_debug_prompt_is_printed_before_every_command() {
+    let instructions = parse_program("debug").unwrap().0;
+    for (prompt, shown) in [(Some("> "), 2), (None, 0)] {
+        let mut state = State::new();
+        state.config.debug_prompt = prompt.map(String::from);
+        let mut output = Vec::new();
+        run(&mut state, &instructions, &mut &b"set r0 1\n\n"[..], &mut output, |_, _, _| ()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().matches("> ").count(), shown);
+    }
+}
+
+#[test]
+fn a_label_defined_twice_is_an_error() {
+    let source = "loop:\ninc r0\nLoop:\nloop: dec r0";
+    let error = parse_program(source).unwrap_err();
+    assert_eq!(error.to_string(), "label `loop` already defined on line 1, redefined on line 4");
+    assert_eq!(error.annotate(source).unwrap(), "4 | loop: dec r0\n  | ^^^^");
+    assert!(parse_program("data: .byte 1\ndata:").is_err());
+}
+
+#[test]
+fn cmovc_moves_after_a_carry() {
+    let instructions = parse_program("add r2, r0, r1\ncmovc r3, r4\ncmovnc r5, r4").unwrap().0;
+    for (a, moved) in [(u64::MAX, [9, 0]), (1, [0, 9])] {
+        let mut state = State::new();
+        state.registers[..5].copy_from_slice(&[a, 2, 0, 0, 9].map(Wrapping));
+        run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+        assert_eq!([state[&3], state[&5]], moved.map(Wrapping));
+        assert_eq!(state.carry, a == u64::MAX);
+    }
+}
+
+#[test]
+fn every_invalid_jump_target_is_reported() {
+    let error = parse_program("j a\ninc r0\njz 9\nl:\njnz b").unwrap_err();
+    let lines: Vec<usize> = error.errors().iter().filter_map(InterpretError::line).collect();
+    assert_eq!((error.line(), lines), (Some(1), vec![1, 3, 5]));
+    assert_eq!(InterpretError::Multiple(Vec::new()).line(), None);
+    assert!(matches!(error.errors()[1], InterpretError::JumpOutOfRange { line: 3, target: 9 }));
+    assert_eq!(error.to_string(), "unknown label `a` on line 1\njump target 9 out of range on line 3\nunknown label `b` on line 5");
+    assert_eq!(error.to_json().lines().count(), 3);
+    let single = parse_program("inc r0\nj a").unwrap_err();
+    assert!(matches!(single, InterpretError::UnknownLabel { line: 2, .. }));
+    assert_eq!(single.errors().len(), 1);
+}
+
+#[test]
+fn isqrt_rounds_down() {
+    let mut state = State::new();
+    state.registers[..4].copy_from_slice(&[144, 143, u64::MAX, 1].map(Wrapping));
+    for reg in 0..4 {
+        Instruction::Isqrt { to: reg + 4, from: reg }.apply(&mut state, reg, &mut &b""[..], &mut Vec::new()).unwrap();
+    }
+    assert_eq!(state.registers[4..], [12, 11, 0xFFFF_FFFF, 1].map(Wrapping));
+    state[&7] = Wrapping(0);
+    Instruction::Isqrt { to: 0, from: 7 }.apply(&mut state, 4, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!((state[&0], state.zero), (Wrapping(0), true));
+}
+
+#[test]
+fn an_inline_routine_with_a_loop_can_be_invoked_twice() {
+    let source = ".inline triple\nmovi r1, 3\nloop: add r2, r2, r0\ndec r1\njnz loop\n.endinline\nmovi r0, 2\nfirst: invoke triple\nmovi r0, 5\ninvoke triple";
+    let expanded = expand_inline(source, &Config::default()).unwrap();
+    assert!(expanded.contains("loop@1: add r2, r2, r0") && expanded.contains("jnz loop@2"), "{}", expanded);
+    let mut state = State::new();
+    let instructions = state.load_program(source).unwrap();
+    assert_eq!(state.source, expanded);
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[2], Wrapping(21));
+    assert_eq!((state.labels["first"], state.labels["loop@1"]), (7, 8));
+    assert!(state.call_stack.is_empty());
+    let errors = [".inline a\ninvoke a\n.endinline\ninvoke a", "invoke b", ".inline a\ninc r0"].map(|source| expand_inline(source, &Config::default()).unwrap_err().to_string());
+    assert_eq!(errors, ["routine `a` invokes itself on line 4", "unknown routine `b` on line 1", "routine `a` on line 1 is missing `.endinline`"]);
+}
+
+#[test]
+fn runtime_errors_are_returned_with_their_line() {
+    let state = run_source("add r2, r0, r1\ndebug\nin r3", &[(0, 2), (1, 3)]).unwrap();
+    assert_eq!(state.registers[2..4], [5, 0].map(Wrapping));
+    let error = run_source("inc r0", &[(9, 1)]).err().unwrap();
+    assert_eq!((&error, error.line()), (&InterpretError::UnknownInitialRegister { reg: 9 }, None));
+    let cases = [
+        ("movi r0, 2\nclamp r1, r0, r2", 2, "clamp lower bound 2 is greater than upper bound 0 on line 2"),
+        (".stack 1\npush r0\npush r0", 3, "stack overflow on line 3"),
+        ("inc r0\nret", 2, "return with an empty call stack on line 2"),
+        ("movi r0, 5000\nload r1, r0", 2, "memory address 5000 out of range on line 2"),
+    ];
+    for (source, line, message) in cases {
+        match run_source(source, &[]) {
+            Err(error @ InterpretError::Runtime { .. }) => assert_eq!((error.line(), error.to_string().as_str()), (Some(line), message)),
+            result => panic!("`{}` gave {:?}", source, result.map(|state| state.registers)),
+        }
+    }
+}
+
+#[test]
+fn strict_comments_only_start_after_whitespace() {
+    let mut state = State::new();
+    state.config.strict_comments = true;
+    let instructions = state.load_program("inc r0 // note\ninc r0 ;note\n# heading\ninc r0").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[0], Wrapping(3));
+    assert!(matches!(state.load_program("inc r0#x"), Err(InterpretError::GarbageInstruction { line: 1, .. }) | Err(InterpretError::Parse { line: 1, .. })));
+    assert!(state.load_program("inc r0;x").is_err());
+    assert_eq!(run_source("inc r0#x\ninc r0;x", &[]).unwrap().registers[0], Wrapping(2));
+}
+
+#[test]
+fn every_problem_in_a_program_is_reported_in_line_order() {
+    let error = State::new().load_program("j nowhere\ninc r9\ninc r0\nfrobnicate r1\nj 99").unwrap_err();
+    let lines: Vec<usize> = error.errors().iter().filter_map(InterpretError::line).collect();
+    assert_eq!(lines, [1, 2, 4, 5]);
+    assert!(matches!(error.errors()[0], InterpretError::UnknownLabel { line: 1, ref name } if name == "nowhere"));
+    assert!(matches!(error.errors()[1], InterpretError::UnknownRegister { line: 2, reg: 9 }));
+    assert!(matches!(State::new().load_program("inc r0\ninc r9"), Err(InterpretError::UnknownRegister { line: 2, .. })));
+}
+
+#[test]
+fn popcntall_counts_the_bits_of_every_register() {
+    let state = run_source("popcntall r0", &[(0, 0b111), (1, u64::MAX), (7, 0x10)]).unwrap();
+    assert_eq!((state.registers[0], state.zero), (Wrapping(68), false));
+    let state = run_source("popcntall r3", &[]).unwrap();
+    assert_eq!((state.registers[3], state.zero), (Wrapping(0), true));
+}
+
+#[test]
+fn accessing_the_trapped_address_opens_the_prompt() {
+    let mut state = State::new();
+    state.config.trap_addr = Some(10);
+    let source = "movi r0, 10\nmovi r1, 4\nmovi r2, 11\nstore r2, r1\nstore r0, r1\nload r3, r0";
+    let instructions = state.load_program(source).unwrap();
+    let mut output = Vec::new();
+    run(&mut state, &instructions, &mut &b"set r5 1\n\nset r6 1\n\n"[..], &mut output, |_, _, _| ()).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(!output.contains("memory[11]"), "{}", output);
+    assert_eq!(output.matches("Trap:").count(), 2);
+    assert!(output.contains("line 5 writes 4 at memory[10]") && output.contains("line 6 reads 4 at memory[10]"), "{}", output);
+    assert_eq!(state.registers[5..7], [Wrapping(1), Wrapping(1)]);
+}
+
+#[test]
+fn the_register_file_can_be_resized_and_narrowed() {
+    let mut state = State::new();
+    state.resize_registers(12);
+    state.config.width = 8;
+    let instructions = state.load_program("movi r11, 255\ninc r11\nmovi r1, 1\ncmp r0, r1\nsumregs r10").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!((state.registers[11], state.registers.len()), (Wrapping(0), 12));
+    assert_eq!((state.sign, state.overflow, state.carry), (true, false, true));
+    assert_eq!(state.registers[10], Wrapping(1));
+    let mut dump = Vec::new();
+    state.write_dump_compact(&mut dump).unwrap();
+    assert!(String::from_utf8(dump).unwrap().ends_with("R10=1 R11=0\n"));
+    assert!(matches!(state.load_program("inc r12"), Err(InterpretError::UnknownRegister { line: 1, reg: 12 })));
+    let mut narrow = State::new();
+    narrow.config.width = 16;
+    let instructions = narrow.load_program("movi r0, 0xffff\ninc r0").unwrap();
+    run(&mut narrow, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!((narrow.registers[0], narrow.zero, narrow.carry), (Wrapping(0), true, true));
+}
+
+#[test]
+fn jsign_branches_three_ways() {
+    let source = "jsign r0, neg, zero, pos\nneg: movi r1, 1\nj end\nzero: movi r1, 2\nj end\npos: movi r1, 3\nend:";
+    for (value, branch) in [(-4i64 as u64, 1), (0, 2), (4, 3)] {
+        assert_eq!(run_source(source, &[(0, value)]).unwrap().registers[1], Wrapping(branch), "{}", value as i64);
+    }
+    assert!(matches!(run_source("jsign r0, 0, 1, missing", &[]), Err(InterpretError::UnknownLabel { line: 1, ref name }) if name == "missing"));
+}
+
+#[test]
+fn aliases_name_registers_after_their_definition() {
+    let state = run_source(".alias counter r3\n.alias total, r4\nmovi counter, 3\nl: add total, total, counter\ndec counter\njnz l", &[]).unwrap();
+    assert_eq!(state.registers[3..5], [0, 6].map(Wrapping));
+    assert_eq!(state.aliases["counter"], 3);
+    let errors = [
+        "inc counter\n.alias counter r3",
+        ".alias r2 r3",
+        ".alias a r1\n.alias a r2",
+        ".alias 9lives r1",
+    ]
+    .map(|source| State::new().load_program(source).unwrap_err().to_string());
+    assert_eq!(errors, [
+        "`counter` is neither a register nor an alias defined above line 1",
+        "alias `r2` shadows a register on line 1",
+        "alias `a` already defined, redefined on line 2",
+        "`9lives` is not a valid alias on line 1",
+    ]);
+}
+
+#[test]
+fn cas_swaps_only_an_expected_value() {
+    let source = "movi r0, 10\nmovi r1, 5\nstore r0, r1\ncas r0, r2, r3";
+    let state = run_source(source, &[(2, 5), (3, 8)]).unwrap();
+    assert_eq!((state.memory[10], state.registers[2], state.zero), (Wrapping(8), Wrapping(5), true));
+    let state = run_source(source, &[(2, 4), (3, 8)]).unwrap();
+    assert_eq!((state.memory[10], state.registers[2], state.zero), (Wrapping(5), Wrapping(5), false));
+    assert!(matches!(run_source("movi r0, 5000\ncas r0, r1, r2", &[]), Err(InterpretError::Runtime { line: 2, .. })));
+}
+
+#[test]
+fn the_debug_prompt_can_single_step() {
+    let mut state = State::new();
+    let instructions = state.load_program("debug\ninc r0\ninc r0\ninc r0\ninc r0").unwrap();
+    let mut output = Vec::new();
+    run(&mut state, &instructions, &mut &b"s\nstep\nc\n"[..], &mut output, |_, _, _| ()).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let steps: Vec<&str> = output.lines().filter(|line| line.contains("Step:")).collect();
+    assert_eq!(steps.len(), 2, "{}", output);
+    assert!(steps[0].ends_with("line 2: `inc     r0`") && steps[1].ends_with("line 3: `inc     r0`"), "{:?}", steps);
+    assert!(!state.single_step);
+    assert_eq!(state.registers[0], Wrapping(4));
+}
+
+#[test]
+fn cooperative_programs_take_turns_over_shared_memory() {
+    let mut first = State::new();
+    let writer = first.load_program(".byte 1, 2\nmovi r0, 3\nmovi r1, 42\nstore r0, r1").unwrap();
+    let mut second = State::new();
+    second.continue_memory(&mut first);
+    // The load comes one turn after the store of the first program.
+    let reader = second.load_program(".byte 3\nmovi r0, 3\ninc r5\ninc r5\nload r2, r0").unwrap();
+    let mut states = [first, second];
+    let mut turns = Vec::new();
+    run_coop(&mut states, &[writer, reader], &mut &b""[..], &mut Vec::new(), |program, _, pc, _| turns.push((program, pc))).unwrap();
+    assert_eq!(turns[..4], [(0, 0), (1, 0), (0, 1), (1, 1)]);
+    assert_eq!(states[1].registers[2], Wrapping(42));
+    assert_eq!(states[0].memory[..4], states[1].memory[..4]);
+    assert_eq!(states[0].memory[..4], [1, 2, 3, 42].map(Wrapping));
+}
+
+#[test]
+fn snapshot_prints_the_registers_with_its_tag() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, 3\nmovi r7, -1\ncmp r0, r0\nsnapshot \"phase 1, done // #2\"").unwrap();
+    let mut output = Vec::new();
+    run(&mut state, &instructions, &mut &b""[..], &mut output, |_, _, _| ()).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "SNAPSHOT phase 1, done // #2 r0=3 r1=0 r2=0 r3=0 r4=0 r5=0 r6=0 r7=18446744073709551615\n");
+    assert!(state.zero);
+    assert_eq!(instructions[3].to_string(), "snapshot \"phase 1, done // #2\"");
+}
+
+#[test]
+fn the_earliest_comment_marker_starts_the_comment() {
+    for line in ["inc r0 // a ; b # c", "inc r0 ; a # b // c", "inc r0 # a // b ; c", "inc r0 # a ; b", "inc r0; a // b", "inc r0 // add r0, r0, #2"] {
+        assert_eq!(run_source(line, &[]).unwrap().registers[0], Wrapping(1), "{}", line);
+    }
+}
+
+#[test]
+fn digits_can_be_separated_by_underscores() {
+    let values = ["1_000_000", "0xFFFF_0000", "0b1010_1010", "-1_0"].map(parse_value);
+    assert_eq!(values, [1_000_000, 0xFFFF_0000, 0b1010_1010, -10i64 as u64].map(Some));
+    assert_eq!(["_1", "1_", "0x_F", "0xF_", "-_1"].map(parse_value), [None; 5]);
+    assert_eq!(interpret_arg("R3=1_000".to_string()), Ok((3, 1000)));
+    assert_eq!(run_source("add r1, r0, 0x1_0", &[]).unwrap().registers[1], Wrapping(16));
+}
+
+#[test]
+fn halteq_ends_the_program_when_the_registers_are_equal() {
+    let state = run_source("l: inc r0\ncmp r0, r0\nhalteq r0, r1\nmovi r2, 1\nj l", &[(1, 3)]).unwrap();
+    assert_eq!((state.registers[0], state.registers[2], state.zero), (Wrapping(3), Wrapping(1), true));
+    let state = run_source("halteq r0, r1\nmovi r2, 1", &[]).unwrap();
+    assert_eq!(state.registers[2], Wrapping(0));
+}
+
+#[test]
+fn jr_jumps_to_an_index_computed_with_lea() {
+    // Jumps through a table of two targets in memory, picking the entry at r5.
+    let source = "lea r1, first\nlea r2, second\nstore r0, r1\ninc r0\nstore r0, r2\nload r3, r5\njr r3\nfirst: movi r4, 1\nj end\nsecond: movi r4, 2\nend:";
+    for (entry, value) in [(0, 1), (1, 2)] {
+        let state = run_source(source, &[(5, entry)]).unwrap();
+        assert_eq!((state.registers[1], state.registers[4]), (Wrapping(7), Wrapping(value)));
+    }
+    assert_eq!(run_source("lea r0, end+1\njr r0\ninc r1\nend:", &[]).unwrap().registers[1], Wrapping(0));
+    assert!(matches!(run_source("movi r0, 3\njr r0", &[]), Err(InterpretError::Runtime { line: 2, message }) if message == "jump to 3 out of range on line 2"));
+}
+
+#[test]
+fn lut_loads_an_entry_of_its_table() {
+    for (index, value) in [(0, 0), (1, 2), (2, 0xFF), (3, 8)] {
+        let state = run_source("lut r0, r1, 0, 2, 0xff, 8", &[(1, index)]).unwrap();
+        assert_eq!((state.registers[0], state.zero), (Wrapping(value), value == 0));
+    }
+    assert!(matches!(run_source("lut r0, r1, 1, 2", &[(1, 2)]), Err(InterpretError::Runtime { line: 1, message }) if message == "table index 2 out of range on line 1"));
+    assert!(State::new().load_program("lut r0, r1").is_err());
+    assert_eq!(State::new().load_program("lut r0, r1, 1, 0x10").unwrap()[0].to_string(), "lut     r0, r1, 1, 16");
+}
+
+#[test]
+fn cmovz_and_cmovnz_move_on_the_zero_flag() {
+    for (start, moved) in [(1, [9, 0]), (2, [0, 9])] {
+        let state = run_source("dec r0\ncmovz r1, r2\ncmovnz r3, r2", &[(0, start), (2, 9)]).unwrap();
+        assert_eq!([state[&1], state[&3]], moved.map(Wrapping));
+        assert_eq!(state.zero, start == 1);
+    }
+}
+
+#[test]
+fn case_insensitive_labels_are_the_same_label() {
+    let mut state = State::new();
+    state.config.case_insensitive_labels = true;
+    let instructions = state.load_program("MOVI r0, 2\nLoop: DEC r0\njnz LOOP").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!((state.registers[0], state.labels["loop"]), (Wrapping(0), 1));
+    let mut state = State::new();
+    state.config.case_insensitive_labels = true;
+    let error = state.load_program("End:\nend:").unwrap_err();
+    assert_eq!(error.to_string(), "label `end` already defined on line 1, redefined on line 2");
+}
+
+#[test]
+fn test_sets_the_zero_flag_from_a_bitwise_and() {
+    let source = "test r0, r1\njz none\nmovi r2, 1\nnone:";
+    for (bits, shared) in [(0b0101, false), (0b0010, true)] {
+        let state = run_source(source, &[(0, 0b1010), (1, bits)]).unwrap();
+        assert_eq!((state.zero, state[&2]), (!shared, Wrapping(shared as u64)));
+        assert_eq!(state.registers[..2], [Wrapping(0b1010), Wrapping(bits)]);
+    }
+}
+
+#[test]
+fn normalize_moves_the_highest_set_bit_to_the_top() {
+    let state = run_source("normalize r1, r0\nnormalize r3, r2", &[(0, 0x10), (2, 0x3 << 62)]).unwrap();
+    assert_eq!([state[&1], state[&3], state[&0]], [Wrapping(1 << 63), Wrapping(0x3 << 62), Wrapping(0x10)]);
+    assert!(!state.zero);
+    let state = run_source("normalize r1, r0", &[]).unwrap();
+    assert_eq!((state[&1], state.zero), (Wrapping(0), true));
+    let mut state = State::new();
+    state.config.width = 8;
+    state[&0] = Wrapping(0b101);
+    Instruction::Normalize { to: 1, from: 0 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!(state[&1], Wrapping(0b1010_0000));
+}
+
+#[test]
+fn the_dump_radix_sets_the_base_of_the_first_column() {
+    let mut state = run_source("movi r0, 5", &[]).unwrap();
+    state.config.width = 8;
+    let mut rows = Vec::new();
+    for radix in [2, 8, 16] {
+        state.config.dump_radix = radix;
+        let mut table = Vec::new();
+        state.write_dump(&mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        rows.push(table.lines().nth(3).unwrap().split_whitespace().collect::<Vec<_>>().join(" "));
+        assert!(table.lines().nth(2).unwrap().contains(["binary", "octal", "hex"][rows.len() - 1]), "{}", table);
+    }
+    assert_eq!(rows, ["R0: 0b00000101 5 0x05", "R0: 0o005 5 0x05", "R0: 0x05 5"]);
+}
+
+#[test]
+fn readline_stores_a_line_of_input_in_memory() {
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, 10\nmovi r1, 8\nreadline r0, r1\nmovi r0, 20\nmovi r2, 3\nreadline r0, r2\nreadline r0, r3").unwrap();
+    run(&mut state, &instructions, &mut &b"hi yo\r\nlonger\n"[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.memory[10..17], "hi yo\0\0".bytes().map(|byte| Wrapping(u64::from(byte))).collect::<Vec<_>>()[..]);
+    assert_eq!(state.memory[20..23], [b'l', b'o', 0].map(|byte| Wrapping(u64::from(byte))));
+    assert_eq!((state[&1], state[&2], state.memory[23]), (Wrapping(5), Wrapping(2), Wrapping(0)));
+    let state = run_source("movi r1, 4\nreadline r0, r1", &[]).unwrap();
+    assert_eq!((state[&1], state.zero), (Wrapping(0), true));
+    let mut state = State::new();
+    let instructions = state.load_program("movi r0, 1023\nmovi r1, 4\nreadline r0, r1").unwrap();
+    let result = run(&mut state, &instructions, &mut &b"ab\n"[..], &mut Vec::new(), |_, _, _| ());
+    assert!(matches!(result, Err(InterpretError::Runtime { line: 3, .. })));
+    let mut state = State::new();
+    state.config.sandbox = true;
+    let instructions = state.load_program("movi r1, 4\nreadline r0, r1").unwrap();
+    let result = run(&mut state, &instructions, &mut &b"hello\n"[..], &mut Vec::new(), |_, _, _| ());
+    assert!(matches!(result, Err(InterpretError::Runtime { line: 2, message }) if message == "`readline` cannot read input in sandbox mode on line 2"));
+    assert_eq!((state.memory[0], state[&1]), (Wrapping(0), Wrapping(4)));
+}
+
+#[test]
+fn halt_ends_the_program_with_an_optional_exit_code() {
+    let state = run_source("inc r0\nhalt\ninc r0", &[]).unwrap();
+    assert_eq!((state[&0], state.exit_code), (Wrapping(1), None));
+    let state = run_source("movi r1, 0x1FF\ncall stop\ninc r0\nstop: halt r1\ninc r0", &[]).unwrap();
+    assert_eq!((state[&0], state.exit_code), (Wrapping(0), Some(0xFF)));
+    let mut state = State::new();
+    state.config.sandbox = true;
+    let instructions = state.load_program("movi r0, 300\nhalt r0\nmovi r1, 1").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!((state.exit_code, state[&1]), (None, Wrapping(0)));
+}
+
+#[test]
+fn bsr_and_bsf_find_the_outer_set_bits() {
+    let source = "bsr r1, r0\nbsf r2, r0";
+    let state = run_source(source, &[(0, 0b1011_0100)]).unwrap();
+    assert_eq!(state.registers[1..3], [Wrapping(7), Wrapping(2)]);
+    let state = run_source(source, &[(0, 1 << 63)]).unwrap();
+    assert_eq!(state.registers[1..3], [Wrapping(63), Wrapping(63)]);
+    // A value of 1 gives the same positions as zero, the zero flag tells them apart.
+    let state = run_source(source, &[(0, 1)]).unwrap();
+    assert_eq!((state.registers[1], state.registers[2], state.zero), (Wrapping(0), Wrapping(0), false));
+    let state = run_source(source, &[(1, 9), (2, 9)]).unwrap();
+    assert_eq!((state.registers[1], state.registers[2], state.zero), (Wrapping(0), Wrapping(0), true));
+}
+
+#[test]
+fn memory_words_can_be_dumped_like_registers() {
+    let mut state = State::new();
+    state.config.dump_memory = 2;
+    let instructions = state.load_program(".byte 7, 8, 9\ndebug").unwrap();
+    let mut output = Vec::new();
+    run(&mut state, &instructions, &mut &b"m 1 2\nm 2 1\n\n"[..], &mut output, |_, _, _| ()).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let rows: Vec<String> = output.lines().filter(|line| line.starts_with('M')).map(|line| line.split_whitespace().collect::<Vec<_>>().join(" ")).collect();
+    // The dump at `debug` shows the first two words, `m 1 2` the second and third.
+    assert_eq!(rows, [
+        "M0: 7 7 0x0000000000000007",
+        "M1: 8 8 0x0000000000000008",
+        "M1: 8 8 0x0000000000000008",
+        "M2: 9 9 0x0000000000000009",
+    ]);
+    let mut dump = Vec::new();
+    state.config.dump_memory = 0;
+    state.write_dump(&mut dump).unwrap();
+    assert!(!String::from_utf8(dump).unwrap().contains("M0"));
+}
+
+/// A writer failing every write, like stdout piped into a closed reader.
+struct Broken;
+
+impl Write for Broken {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn division_by_zero_and_io_errors_have_their_own_variants() {
+    let mut state = State::new();
+    let instructions = state.load_program("inc r0\nout r0").unwrap();
+    match run(&mut state, &instructions, &mut &b""[..], &mut Broken, |_, _, _| ()) {
+        Err(error @ InterpretError::Io { line: 2, .. }) => assert_eq!((error.kind(), error.to_string().as_str()), ("Io", "IO error on line 2: broken pipe")),
+        result => panic!("ran into {:?}", result.err()),
+    }
+    for source in ["inc r0\ninc r0\nmod r0, r0, r1", "start:\ninc r0\ndiv r0, r0, r1"] {
+        let error = run_source(source, &[]).err().unwrap();
+        assert!(matches!(error, InterpretError::DivideByZero { line: 3 }), "{:?}", error);
+        assert_eq!((error.kind(), error.to_string().as_str()), ("DivideByZero", "division by zero on line 3"));
+    }
+}
+
+#[test]
+fn quit_at_the_debug_prompt_is_returned_to_the_caller() {
+    let mut state = State::new();
+    let instructions = state.load_program("debug\nmovi r0, 1").unwrap();
+    let mut output = Vec::new();
+    let result = run(&mut state, &instructions, &mut &b"set r1 5\nbogus\nrestore nothing\nq\n"[..], &mut output, |_, _, _| ());
+    assert!(matches!(result, Err(InterpretError::Quit { line: 1 })));
+    assert_eq!(state.registers[..2], [Wrapping(0), Wrapping(5)]);
+    // Mistyped commands are reported on the prompt's output and it asks again.
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("garbage instruction `bogus`") && output.contains("no snapshot named `nothing`"), "{}", output);
+}
+
+#[test]
+fn the_prompt_after_the_run_ignores_empty_lines() {
+    let mut state = run_source("movi r0, 7", &[]).unwrap();
+    let mut output = Vec::new();
+    let result = debug_prompt(&mut state, 1, true, &mut &b"dump\n\nset r1 9\nc\nquit\n"[..], &mut output);
+    assert!(matches!(result, Err(InterpretError::Quit { line: 2 })));
+    assert_eq!(state.registers[..2], [Wrapping(7), Wrapping(9)]);
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("R0:                     7") && output.contains("the program has finished"), "{}", output);
+}
+
+#[test]
+fn lea_and_movi_load_the_address_of_a_data_label() {
+    let source = "lea r0, bytes\nload r1, r0\nmovi r2, bytes+1\nload r3, r2\nmovi r4, text\nloadd r5, r4, 1\nj end\nbytes: .byte 7, 8\ntext: .ascii \"hi\"\nend:";
+    let state = run_source(source, &[]).unwrap();
+    assert_eq!(state.registers[..6], [0, 7, 1, 8, 2, u64::from(b'i')].map(Wrapping));
+    // A jump still cannot target the line of a data directive.
+    assert!(matches!(parse_program("j bytes\nbytes: .byte 1"), Err(InterpretError::UnknownLabel { line: 1, name }) if name == "bytes"));
+}
+
+#[test]
+fn arithmetic_and_rotations_follow_a_narrow_width() {
+    let source = "movi r0, 0x81\nrol r0, 1\nmovi r1, 0x81\nror r1, 1\nmovi r2, 0xFF\nabs r2, r2\nmovi r3, 0xFF\nmulsat r3, r3, r3\nmovi r4, 0xFF\nmul r4, r5, r4, r4\nmovi r6, 0xF9\nmovi r7, 2\nidiv r6, r6, r7";
+    let mut state = State::new();
+    state.config.width = 8;
+    let instructions = state.load_program(source).unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    // -1 has the absolute value 1, 255 * 255 saturates, its high half is 0xFE and -7 / 2 is -3.
+    assert_eq!(state.registers, [0x03, 0xC0, 1, 0xFF, 0xFE, 0x01, 0xFD, 2].map(Wrapping));
+    let mut state = State::new();
+    state.config.width = 8;
+    let instructions = state.load_program("movi r0, 0xF9\nmovi r1, 2\nimod r0, r0, r1\nmovi r2, 0x80\nabs r2, r2\nmovi r3, 0x81\nmovi r4, 9\nrolr r3, r4").unwrap();
+    run(&mut state, &instructions, &mut &b""[..], &mut Vec::new(), |_, _, _| ()).unwrap();
+    assert_eq!(state.registers[..4], [0xFF, 2, 0x7F, 0x03].map(Wrapping));
+}
+
+#[test]
+fn every_immediate_can_be_prefixed_with_a_hash() {
+    let source = "movi r0, #5\nadd r1, r0, #-5\nadd r2, r2, #'A' # a comment\nmovi r3, 7 #comment\nstored r0, r2, #0x1\nlut r4, r5, #9, #0b1";
+    let state = run_source(source, &[]).unwrap();
+    assert_eq!(state.registers[..5], [5, 0, 65, 7, 9].map(Wrapping));
+    assert_eq!(state.memory[66], Wrapping(5));
+    assert_eq!(run_source("movi r0, #'#' # not the quoted one", &[]).unwrap().registers[0], Wrapping(u64::from(b'#')));
+    assert!(parse_program("movi r0, #abc").is_err());
+}
+
+#[test]
+fn cooperative_programs_have_their_own_stacks() {
+    let mut first = State::new();
+    let mut programs = vec![first.load_program("counter: .byte 0\nlea r1, counter\nmovi r0, 11\npush r0\nincm r1\nincm r1\npop r2").unwrap()];
+    let mut second = State::new();
+    second.continue_memory(&mut first);
+    // The first program got the default stack size, so the second one can't take more than what is left.
+    assert!(second.load_program(&format!(".stack {}", MEMORY_WORDS - SHARED_STACK_WORDS)).is_err());
+    programs.push(second.load_program("movi r1, 0\nmovi r0, 22\npush r0\nincm r1\nincm r1\npop r2").unwrap());
+    let mut states = [first, second];
+    run_coop(&mut states, &programs, &mut &b""[..], &mut Vec::new(), |_, _, _, _| ()).unwrap();
+    assert_eq!([states[0][&2], states[1][&2], states[1].memory[0]], [11, 22, 4].map(Wrapping));
+    // A program another one follows overflows its stack after `SHARED_STACK_WORDS` pushes.
+    let mut first = State::new();
+    State::new().continue_memory(&mut first);
+    let pushes = vec![Instruction::Push { from: 0 }; SHARED_STACK_WORDS + 1];
+    match run_coop(&mut [first], &[pushes], &mut &b""[..], &mut Vec::new(), |_, _, _, _| ()) {
+        Err((0, InterpretError::Runtime { line, .. })) => assert_eq!(line, SHARED_STACK_WORDS + 1),
+        result => panic!("ran into {:?}", result.err()),
+    }
+}
+
+#[test]
+fn fingerprints_change_with_every_write_to_memory() {
+    let mut state = State::new();
+    let before = state.fingerprint();
+    assert_eq!(state.fingerprint(), before);
+    Instruction::Store { addr: 0, from: 1 }.apply(&mut state, 0, &mut &b""[..], &mut Vec::new()).unwrap();
+    // The word was already zero, but it was written.
+    assert_eq!(state.memory[0], Wrapping(0));
+    assert_ne!(state.fingerprint(), before);
+    let stored = state.fingerprint();
+    Instruction::Load { to: 1, addr: 0 }.apply(&mut state, 1, &mut &b""[..], &mut Vec::new()).unwrap();
+    assert_eq!(state.fingerprint(), stored);
+}
@@ -0,0 +1,662 @@
+//! Runs the interpreter binary on small programs and checks what it prints and exits with.
+
+use std::{io::{BufRead, BufReader, Write}, process::{Command, Output, Stdio}};
+
+/// Runs the interpreter on `source`, written to a temporary file, with `args` after the file and `input` on stdin.
+fn interpret(name: &str, source: &str, args: &[&str], input: &str) -> Output {
+    let path = std::env::temp_dir().join(format!("basic_asm_interpreter_{}_{}.s", name, std::process::id()));
+    std::fs::write(&path, source).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_basic_asm_interpreter"))
+        .arg(&path)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn dump_every_prints_a_dump_every_n_steps() {
+    let output = interpret("dump_every", "l:\ndec r0\njnz l\n", &["--dump-every", "2", "r0=2"], "");
+    assert!(output.status.success());
+    let printed = stdout(&output);
+    let dumps: Vec<&str> = printed.lines().filter(|line| line.contains("Step")).collect();
+    assert_eq!(dumps.len(), 3);
+    for (dump, (step, zero, r0)) in dumps.iter().zip([(2, false, 1), (4, false, 1), (6, true, 0)]) {
+        assert!(dump.contains(&format!("Step {}:", step)), "{}", dump);
+        assert!(dump.ends_with(&format!("Zero: {} Carry: false Sign: false Overflow: false R0={} R1=0 R2=0 R3=0 R4=0 R5=0 R6=0 R7=0", zero, r0)), "{}", dump);
+    }
+    let output = interpret("no_dump_every", "l:\ndec r0\njnz l\n", &["r0=2"], "");
+    assert!(!stdout(&output).contains("Step"));
+}
+
+#[test]
+fn emitted_programs_run_like_their_source() {
+    let source = "l:\ninc r1\ndec r0\njnz l\nj end\ninc r2\nend:\n";
+    let emitted = interpret("emit_source", source, &["--emit-resolved"], "");
+    assert!(emitted.status.success());
+    let emitted = stdout(&emitted);
+    assert_eq!(emitted.lines().count(), source.lines().count());
+    assert!(emitted.contains("jnz     0\n") && emitted.contains("j       6\n"));
+    let original = interpret("emit_original", source, &["r0=3"], "");
+    let round_trip = interpret("emit_round_trip", &emitted, &["r0=3"], "");
+    assert_eq!(stdout(&original), stdout(&round_trip));
+    assert!(stdout(&original).contains("R1:                     3"));
+}
+
+#[test]
+fn steps_counts_the_instructions_executed_before_it() {
+    let output = interpret("steps", "l:\ndec r0\njnz l\nsteps r1\nsteps r2\n", &["r0=2"], "");
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("R1:                     6"));
+    assert!(stdout(&output).contains("R2:                     7"));
+}
+
+#[test]
+fn listings_use_the_canonical_format() {
+    let source = "l:\nadd r1,r0,  r2\nshl r1, 255\njnz l\ndebug\n";
+    let decimal = interpret("listing_decimal", source, &["--emit-resolved"], "");
+    assert_eq!(stdout(&decimal), "\nadd     r1, r0, r2\nshl     r1, 255\njnz     0\ndebug\n");
+    let hex = interpret("listing_hex", source, &["--emit-resolved", "--disasm-hex"], "");
+    assert_eq!(stdout(&hex), "\nadd     r1, r0, r2\nshl     r1, 0xFF\njnz     0\ndebug\n");
+}
+
+#[test]
+fn stuck_detect_stops_a_loop_without_progress() {
+    let stuck = interpret("stuck", "inc r0\nl:\nj l\n", &["--stuck-detect"], "");
+    assert_eq!(stuck.status.code(), Some(1));
+    assert!(stderr(&stuck).contains("no-progress loop detected at line 2"));
+    let counting = interpret("counting", "l:\ndec r0\njnz l\n", &["--stuck-detect", "r0=3"], "");
+    assert!(counting.status.success());
+    assert_eq!(stderr(&counting), "");
+    // Only memory changes from one iteration to the next.
+    let in_memory = interpret("counting_in_memory", ".byte 3
+zero r0
+l: decm r0
+jnz l
+", &["--stuck-detect", "--quiet"], "");
+    assert!(in_memory.status.success(), "{}", stderr(&in_memory));
+    let pushing = interpret("pushing", "l: push r0
+j l
+", &["--stuck-detect", "--quiet"], "");
+    assert!(stderr(&pushing).contains("stack overflow"), "{}", stderr(&pushing));
+}
+
+#[test]
+fn loopn_iterates_up_to_a_limit_in_a_register() {
+    for limit in ["1", "5"] {
+        let output = interpret("loopn", "l:\ninc r2\nloopn r0, r1, l\n", &[&format!("r1={}", limit)], "");
+        let dump = stdout(&output);
+        assert!(dump.contains("Zero: true"), "{}", dump);
+        for reg in 0..3 {
+            assert!(dump.contains(&format!("R{}:  {:>20}", reg, limit)), "{}", dump);
+        }
+    }
+}
+
+#[test]
+fn no_dump_keeps_only_the_banner() {
+    let output = interpret("no_dump", "inc r0\n", &["--no-dump"], "");
+    let banner = stdout(&output);
+    assert!(banner.contains("Finished:"));
+    assert!(!banner.contains("R0:") && !banner.contains("Zero:"));
+    let output = interpret("with_dump", "inc r0\n", &[], "");
+    assert!(stdout(&output).starts_with(&banner) && stdout(&output).contains("R0:"));
+    let output = interpret("quiet", "inc r0\n", &["--no-dump", "--quiet"], "");
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn paddb_wraps_each_byte_on_its_own() {
+    let output = interpret("paddb", "paddb r2, r0, r1\n", &["r0=511", "r1=257"], "");
+    assert!(stdout(&output).contains("0x0000000000000200"), "{}", stdout(&output));
+}
+
+#[test]
+fn pcmpeqb_marks_the_equal_bytes() {
+    let output = interpret("pcmpeqb", "pcmpeqb r2, r0, r1\n", &["r0=287454020", "r1=285225728"], "");
+    let dump = stdout(&output);
+    assert!(dump.contains("Zero: false"));
+    assert!(dump.lines().any(|line| line.starts_with("R2:") && line.ends_with("0xFFFFFFFFFF00FF00")), "{}", dump);
+}
+
+#[test]
+fn saved_states_can_be_loaded() {
+    let path = std::env::temp_dir().join(format!("basic_asm_interpreter_state_{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    let saved = interpret("save_state", "dec r3\nzero r1\nstore r0, r3\n", &["--save-state", path, "--quiet", "r0=7", "r1=5"], "");
+    assert!(saved.status.success());
+    let json = std::fs::read_to_string(path).unwrap();
+    assert!(json.starts_with("{\"registers\": [7, 0, 0, 18446744073709551615, 0, 0, 0, 0], \"zero\": false"), "{}", json);
+    assert!(json.contains("\"memory\": {\"7\": 18446744073709551615}"), "{}", json);
+    let loaded = interpret("load_state", "load r5, r0\ninc r0\n", &["--load-state", path, "r2=4"], "");
+    std::fs::remove_file(path).unwrap();
+    let dump = stdout(&loaded);
+    assert!(dump.contains("R0:                     8") && dump.contains("R2:                     4"), "{}", dump);
+    assert!(dump.contains("R3:  18446744073709551615") && dump.contains("R5:  18446744073709551615"), "{}", dump);
+}
+
+#[test]
+fn sign_extension_copies_the_top_bit_of_each_width() {
+    for (mnemonic, set, negative, clear, positive) in [
+        ("sxtb", "384", "0xFFFFFFFFFFFFFF80", "383", "0x000000000000007F"),
+        ("sxtw", "98304", "0xFFFFFFFFFFFF8000", "98303", "0x0000000000007FFF"),
+        ("sxtl", "6442450944", "0xFFFFFFFF80000000", "6442450943", "0x000000007FFFFFFF"),
+    ] {
+        let source = format!("{} r0\n{} r1\n", mnemonic, mnemonic);
+        let output = interpret(mnemonic, &source, &[&format!("r0={}", set), &format!("r1={}", clear)], "");
+        let dump = stdout(&output);
+        assert!(dump.lines().any(|line| line.starts_with("R0:") && line.ends_with(negative)), "{}", dump);
+        assert!(dump.lines().any(|line| line.starts_with("R1:") && line.ends_with(positive)), "{}", dump);
+    }
+}
+
+#[test]
+fn zero_extension_masks_to_the_low_bits() {
+    let max = "r0=18446744073709551615";
+    for (mnemonic, masked) in [("zxtb", "0x00000000000000FF"), ("zxtw", "0x000000000000FFFF"), ("zxtl", "0x00000000FFFFFFFF")] {
+        let output = interpret(mnemonic, &format!("{} r0\n", mnemonic), &[max], "");
+        let dump = stdout(&output);
+        assert!(dump.contains("Zero: false") && dump.lines().any(|line| line.starts_with("R0:") && line.ends_with(masked)), "{}", dump);
+    }
+    let output = interpret("zxtb_zero", "zxtb r0\n", &["r0=256"], "");
+    assert!(stdout(&output).contains("Zero: true"));
+}
+
+#[test]
+fn interactive_inspects_the_state_after_the_run() {
+    let output = interpret("interactive", "l:\ndec r0\njnz l\n", &["--interactive", "--quiet", "r0=3"], "dump\nlabels\n\nset r1 5\ninc r1\nquit\ninc r1\n");
+    assert!(output.status.success());
+    let session = stdout(&output);
+    assert!(session.starts_with("Zero: true Carry: false Sign: false Overflow: false\n") && session.contains("R0:                     0"));
+    assert!(session.contains("l: line 1\n"));
+    assert!(session.ends_with("Zero: false Carry: false Sign: false Overflow: false R0=0 R1=6 R2=0 R3=0 R4=0 R5=0 R6=0 R7=0\n"));
+}
+
+#[test]
+fn minmax_orders_two_registers() {
+    let ordered = stdout(&interpret("minmax_ordered", "minmax r0, r1\n", &["r0=3", "r1=9"], ""));
+    assert!(ordered.contains("Zero: true") && ordered.contains("R0:                     3") && ordered.contains("R1:                     9"));
+    let swapped = stdout(&interpret("minmax_swapped", "minmax r0, r1\n", &["r0=9", "r1=3"], ""));
+    assert!(swapped.contains("Zero: false") && swapped.contains("R0:                     3") && swapped.contains("R1:                     9"));
+}
+
+#[test]
+fn label_output_names_the_source_in_the_banner() {
+    let labelled = interpret("label_banner", "inc r0\n", &["--label-output", "--no-dump"], "");
+    let name = format!("basic_asm_interpreter_label_banner_{}.s", std::process::id());
+    assert!(stdout(&labelled).contains("Finished:") && stdout(&labelled).ends_with(&format!("{}\n", name)));
+    let plain = interpret("plain_banner", "inc r0\n", &["--no-dump"], "");
+    assert!(!stdout(&plain).contains("plain_banner"));
+}
+
+#[test]
+fn memory_out_of_range_is_an_error_unless_soft() {
+    let source = "store r0, r1\nload r2, r0\nstore r3, r1\nload r4, r3\n";
+    let args = ["r0=1024", "r1=7", "r2=5", "r3=1023"];
+    let hard = interpret("hard_memory", source, &args, "");
+    assert_eq!(hard.status.code(), Some(1));
+    assert!(stderr(&hard).contains("memory address 1024 out of range on line 1"));
+    let soft = interpret("soft_memory", source, &[&args[..], &["--soft-memory"]].concat(), "");
+    assert!(soft.status.success());
+    let dump = stdout(&soft);
+    assert!(dump.contains("R2:                     0") && dump.contains("R4:                     7"), "{}", dump);
+}
+
+#[test]
+fn trace_jumps_logs_only_taken_jumps() {
+    let output = interpret("trace_jumps", "l:\ndec r0\njnz l\nj end\nend:\n", &["--trace-jumps", "--quiet", "r0=3"], "");
+    let jumps: Vec<String> = stderr(&output).lines().map(String::from).collect();
+    assert_eq!(jumps.len(), 2);
+    assert!(jumps.iter().all(|jump| jump.ends_with("line 3: `jnz l` (zero flag clear) -> line 1")), "{:?}", jumps);
+}
+
+#[test]
+fn warn_uninit_flags_registers_read_before_being_set() {
+    let output = interpret("warn_uninit", "add r0, r1, r2\nzero r3\ninc r3\ninc r4\n", &["--warn-uninit", "--quiet", "r1=2"], "");
+    assert!(output.status.success());
+    let warnings: Vec<String> = stderr(&output).lines().map(String::from).collect();
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[0].ends_with("r2 is read on line 1 before it is written"));
+    assert!(warnings[1].ends_with("r4 is read on line 4 before it is written"));
+}
+
+#[cfg(unix)]
+#[test]
+fn an_interrupt_dumps_the_state_where_it_stopped() {
+    let path = std::env::temp_dir().join(format!("basic_asm_interpreter_interrupt_{}.s", std::process::id()));
+    std::fs::write(&path, "inc r0\nout r0\nl:\nj l\n").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_basic_asm_interpreter"))
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The handler is installed before the program runs, so the line printed by `out` means it is ready.
+    let mut ready = String::new();
+    BufReader::new(child.stdout.as_mut().unwrap()).read_line(&mut ready).unwrap();
+    assert_eq!(ready, "1\n");
+    Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(130));
+    // The loop alternates between its label and its jump, so it may stop at either.
+    assert!(stdout(&output).contains("Interrupted:"));
+    assert!(stdout(&output).contains("line 3") || stdout(&output).contains("line 4"));
+    assert!(stdout(&output).contains("R0:                     1"));
+}
+
+#[test]
+fn abs_saturates_unless_overflow_is_strict() {
+    let source = "abs r0, r0\nabs r1, r1\nabs r2, r2\n";
+    let args = ["r0=5", "r1=-5", "r2=-9223372036854775808"];
+    let dump = stdout(&interpret("abs", source, &args, ""));
+    assert!(dump.contains("R0:                     5") && dump.contains("R1:                     5"), "{}", dump);
+    assert!(dump.contains("R2:   9223372036854775807"), "{}", dump);
+    let strict = interpret("abs_strict", source, &[&args[..], &["--strict-overflow"]].concat(), "");
+    assert_eq!(strict.status.code(), Some(1));
+    assert!(stderr(&strict).contains("absolute value of -9223372036854775808 overflows on line 3"));
+}
+
+#[test]
+fn goto_mark_continues_from_the_marked_line() {
+    let output = interpret("goto_mark", "debug\ninc r0\ndebug\n", &["--quiet"], "goto-mark\nmark\n\ngoto-mark\n\n\n");
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("no mark has been set"));
+    assert_eq!(stdout(&output).matches("Zero:").count(), 4);
+    let output = interpret("goto_mark_dump", "debug\ninc r0\ndebug\n", &[], "mark\n\ngoto-mark\n\n\n");
+    assert!(stdout(&output).contains("R0:                     2"));
+}
+
+#[test]
+fn dump_columns_line_up_for_every_value() {
+    let args = ["r0=-1", "r1=5", "r2=9223372036854775808"];
+    let wide = stdout(&interpret("format_wide", "", &[&args[..], &["--format", "table-wide"]].concat(), ""));
+    let table: Vec<&str> = wide.lines().skip_while(|line| !line.contains("unsigned")).collect();
+    assert_eq!(table[0], "                 unsigned                signed                 hex");
+    assert_eq!(table[3], "R2:   9223372036854775808  -9223372036854775808  0x8000000000000000");
+    assert!(table.iter().all(|line| line.len() == table[0].len()));
+    let narrow = stdout(&interpret("format_narrow", "", &[&args[..], &["--format=table-narrow"]].concat(), ""));
+    let table: Vec<&str> = narrow.lines().skip_while(|line| !line.contains("hex")).collect();
+    assert_eq!(table[..2], ["                    hex", "R0:  0xFFFFFFFFFFFFFFFF"]);
+    assert!(table.iter().all(|line| line.len() == table[0].len()));
+}
+
+#[test]
+fn sandbox_mode_reads_no_input_and_writes_no_files() {
+    let output = interpret("sandbox", "debug\ninc r0\n", &["--sandbox", "--quiet"], "set r1 1\n\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).matches("Zero:").count(), 1);
+    assert!(!stdout(&output).contains("R1=1"));
+    for (options, error) in [
+        (&["--save-state", "state.json"][..], "--save-state writes a file and cannot be used with --sandbox"),
+        (&["--interactive"][..], "--interactive reads input and cannot be used with --sandbox"),
+    ] {
+        let output = interpret("sandbox_rejects", "inc r0\n", &[options, &["--sandbox"]].concat(), "");
+        assert_eq!(output.status.code(), Some(1));
+        assert!(stderr(&output).contains(error), "{}", stderr(&output));
+    }
+}
+
+#[test]
+fn sandbox_mode_limits_the_output_and_the_memory() {
+    let output = interpret("sandbox_output", "movi r0, 65\nl: putc r0\nj l\n", &["--sandbox", "--limit-output", "3", "--quiet"], "");
+    assert_eq!((output.status.code(), stdout(&output).as_str()), (Some(1), "AAA"));
+    assert!(stderr(&output).contains("IO error on line 2: output limit of 3 bytes reached"), "{}", stderr(&output));
+    let output = interpret("sandbox_memory", "inc r0\n", &["--sandbox", "--memory-words", "100000"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("memory of 100000 words exceeds the limit of 65536 words"), "{}", stderr(&output));
+    let output = interpret("sandbox_steps", "l: j l\n", &["--sandbox", "--max-steps", "0"], "");
+    assert!(stderr(&output).contains("step limit of 1000000 reached in sandbox mode at line 1"), "{}", stderr(&output));
+}
+
+#[test]
+fn diff_state_compares_the_final_state() {
+    let path = std::env::temp_dir().join(format!("basic_asm_interpreter_expected_{}.json", std::process::id()));
+    std::fs::write(&path, "{\"registers\": [3, 0, 0, 0, 0, 0, 0, 0], \"zero\": false}\n").unwrap();
+    let expected = path.to_str().unwrap();
+    let matching = interpret("diff_match", "inc r0\n", &["--diff-state", expected, "--quiet", "r0=2"], "");
+    let mismatching = interpret("diff_mismatch", "inc r0\ninc r5\n", &["--diff-state", expected, "--quiet", "r0=1"], "");
+    let zero = interpret("diff_zero", "dec r1\n", &["--diff-state", expected, "--quiet", "r0=3", "r1=1"], "");
+    std::fs::remove_file(&path).unwrap();
+    assert!(matching.status.success());
+    assert_eq!(stdout(&matching), "");
+    assert_eq!(mismatching.status.code(), Some(1));
+    let diff = format!("final state differs from `{}`\nR0: expected 3, got 2\nR5: expected 0, got 1\n", expected);
+    assert!(stdout(&mismatching).ends_with(&diff), "{}", stdout(&mismatching));
+    assert_eq!(zero.status.code(), Some(1));
+    assert!(stdout(&zero).ends_with("\nZero: expected false, got true\n"));
+}
+
+#[test]
+fn foreach_runs_once_per_input_line() {
+    let output = interpret("foreach", "add r2, r0, r1\n", &["--foreach"], "1 2\n3\n9 9 9\n");
+    assert!(output.status.success());
+    let runs: Vec<String> = stdout(&output).lines().map(String::from).collect();
+    assert_eq!(runs.len(), 3);
+    for (run, registers) in runs.iter().zip(["R0=1 R1=2 R2=3 R3=0", "R0=3 R1=0 R2=3 R3=0", "R0=9 R1=9 R2=18 R3=0"]) {
+        assert!(run.contains(registers), "{}", run);
+    }
+    let bad = interpret("foreach_bad", "add r2, r0, r1\n", &["--foreach"], "1 x\n3\n");
+    assert_eq!((bad.status.code(), stdout(&bad).as_str()), (Some(1), ""));
+    let skipped = interpret("foreach_skip", "add r2, r0, r1\n", &["--foreach", "--skip-bad"], "1 x\n3\n");
+    assert!(stderr(&skipped).contains("skipping malformed input line 1: `1 x`"));
+    assert!(stdout(&skipped).contains("R0=3 R1=0 R2=3") && stdout(&skipped).lines().count() == 1);
+}
+
+#[test]
+fn explain_describes_each_instruction() {
+    let source = "add r0, r1, r2\njz l\nsub r0, r0, r0\nl:\njz end\nend:\n";
+    let output = interpret("explain", source, &["--explain", "--quiet", "r1=5", "r2=3"], "");
+    let explanations: Vec<String> = stderr(&output).lines().map(String::from).collect();
+    assert_eq!(explanations.len(), 6);
+    assert!(explanations[0].ends_with("line 1: `add r0, r1, r2`: r0 = r1 + r2 = 5 + 3 = 8"));
+    assert!(explanations[1].ends_with("line 2: `jz l`: zero flag clear, no jump"), "{}", explanations[1]);
+    assert!(explanations[2].ends_with("line 3: `sub r0, r0, r0`: r0 = r0 - r0 = 8 - 8 = 0; zero flag set"));
+    assert!(explanations[4].ends_with("line 5: `jz end`: zero flag set, jump to line 6"));
+}
+
+#[test]
+fn check_contract_requires_the_inputs() {
+    let source = ".input r0, r1\n.output r2\nadd r2, r0, r1\n";
+    let missing = interpret("contract_missing", source, &["--check-contract", "--quiet", "r0=1"], "");
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(stderr(&missing).contains("input r1 is read on line 3 before it is set"));
+    let complete = interpret("contract_complete", source, &["--check-contract", "--quiet", "r0=1", "r1=2"], "");
+    assert!(complete.status.success());
+    assert_eq!(stderr(&complete), "");
+    let unwritten = interpret("contract_output", ".output r2\ninc r0\n", &["--check-contract", "--quiet"], "");
+    assert!(stderr(&unwritten).contains("output r2 is never written"));
+}
+
+#[test]
+fn modexp_with_a_zero_modulus_is_an_error() {
+    let output = interpret("modexp_zero", "inc r1\nmodexp r0, r1, r1, r2\n", &[], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("modexp with a zero modulus on line 2"), "{}", stderr(&output));
+}
+
+#[test]
+fn dividing_by_zero_is_an_error() {
+    let output = interpret("div_zero", "inc r0\nmod r1, r0, r2\n", &[], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("division by zero on line 2"), "{}", stderr(&output));
+    let output = interpret("idiv_overflow", "movi r0, 0x8000000000000000\nmovi r1, -1\nidiv r0, r0, r1\n", &["--strict-overflow"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("overflows on line 3"), "{}", stderr(&output));
+}
+
+#[test]
+fn a_recorded_session_replays_the_same() {
+    let path = std::env::temp_dir().join(format!("basic_asm_interpreter_record_{}.txt", std::process::id()));
+    let path = path.to_str().unwrap();
+    let source = "inc r0\ndebug\nadd r2, r0, r1\n";
+    let recorded = interpret("record", source, &["--record", path], "set r1 7\ndump\n\nnot read\n");
+    assert!(recorded.status.success());
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "set r1 7\ndump\n\n");
+    let replayed = interpret("replay", source, &["--replay", path], "");
+    std::fs::remove_file(path).unwrap();
+    assert!(stdout(&recorded).lines().any(|line| line.starts_with("R2:") && line.contains(" 8 ")), "{}", stdout(&recorded));
+    assert_eq!(stdout(&recorded), stdout(&replayed));
+}
+
+#[test]
+fn memory_is_capped_and_reported() {
+    let source = "push r0\npush r0\npop r1\n";
+    let capped = interpret("mem_cap", source, &["--memory-words", "2048", "--max-mem-words", "1024"], "");
+    assert_eq!(capped.status.code(), Some(1));
+    assert!(stderr(&capped).contains("memory of 2048 words exceeds the limit of 1024 words set with --max-mem-words"));
+    let stats = interpret("mem_stats", source, &["--memory-words", "512", "--max-mem-words", "512", "--stats"], "");
+    assert!(stats.status.success());
+    assert!(stdout(&stats).contains("Steps: 3\nInstructions: 3\nMemory words: 512\nPeak stack depth: 2\n"), "{}", stdout(&stats));
+}
+
+#[test]
+fn warn_fallthrough_warns_once_per_label() {
+    let output = interpret("fallthrough", "movi r0, 2\nback:\nnext:\ndec r0\njnz back\n", &["--warn-fallthrough", "--quiet"], "");
+    assert!(output.status.success());
+    let warnings = stderr(&output);
+    let warnings: Vec<&str> = warnings.lines().collect();
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+    assert!(warnings[0].ends_with("line 1 falls through into label `back` on line 2"), "{}", warnings[0]);
+    assert!(warnings[1].ends_with("line 2 falls through into label `next` on line 3"), "{}", warnings[1]);
+    let output = interpret("no_fallthrough", "j loop\nloop:\ndec r0\njnz loop\n", &["--warn-fallthrough", "--quiet", "r0=2"], "");
+    assert!(output.status.success());
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn in_rejects_input_that_is_not_a_value() {
+    let output = interpret("in_invalid", "in r0\nin r1\n", &[], "3\nthree\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("invalid input `three` on line 2"), "{}", stderr(&output));
+}
+
+#[test]
+fn json_errors_have_a_kind_a_line_and_a_message() {
+    let output = interpret("json_error", "inc r0\nadd r0, r9, r1\n", &["--error-format", "json"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "{\"kind\":\"UnknownRegister\",\"line\":2,\"message\":\"r9 does not exist\"}\n");
+    let output = interpret("json_run_error", "inc r0\ndiv r0, r0, r1\n", &["--error-format", "json"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "{\"kind\":\"DivideByZero\",\"line\":2,\"message\":\"division by zero on line 2\"}\n");
+    let output = interpret("json_arg_error", "inc r0\n", &["--error-format", "json", "r9=1"], "");
+    assert_eq!(stderr(&output), "{\"kind\":\"Error\",\"line\":null,\"message\":\"r9 does not exist\"}\n");
+    let output = interpret("json_format_error", "inc r0\n", &["--error-format", "xml"], "");
+    assert!(stderr(&output).contains("unknown error format `xml`, expected `human` or `json`"), "{}", stderr(&output));
+}
+
+#[test]
+fn memset_beyond_memory_is_an_error() {
+    let output = interpret("memset_out", "movi r0, 1020\nmovi r2, 5\nmemset r0, r1, r2\n", &[], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("memory block of 5 words at address 1020 out of range on line 3"), "{}", stderr(&output));
+}
+
+#[test]
+fn the_debug_prompt_is_not_printed_for_piped_input() {
+    let output = interpret("debug_prompt", "debug\n", &["--debug-prompt", "> ", "--quiet"], "set r0 1\n\n");
+    assert!(output.status.success());
+    assert!(!stdout(&output).contains("> ") && !stdout(&output).contains("(debug)"), "{}", stdout(&output));
+}
+
+#[test]
+fn animate_dumps_after_every_burst() {
+    let output = interpret("animate", "movi r0, 3\nl:\ndec r0\njnz l\n", &["--animate", "3", "--delay", "0", "--quiet"], "");
+    assert!(output.status.success());
+    let printed = stdout(&output);
+    let r0: Vec<&str> = printed.lines().map(|dump| dump.split(' ').find(|field| field.starts_with("R0=")).unwrap()).collect();
+    assert_eq!(r0, ["R0=2", "R0=1", "R0=0"]);
+    let output = interpret("delay_alone", "inc r0\n", &["--delay", "10"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("cannot be used without it"));
+}
+
+#[test]
+fn max_steps_stops_a_runaway_loop() {
+    let output = interpret("max_steps", "inc r0\nloop:\nj loop\n", &["--max-steps", "5"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("step limit of 5 reached at line 2, raise it with --max-steps"), "{}", stderr(&output));
+    let output = interpret("max_steps_off", "movi r0, 20\nl:\ndec r0\njnz l\n", &["--max-steps", "0", "--quiet"], "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn inline_routines_are_traced_and_annotated_as_expanded() {
+    let output = interpret("inline_trace", ".inline bump\ninc r0\n.endinline\ninvoke bump\ninvoke bump\n", &["--trace", "--quiet"], "");
+    let log = stderr(&output);
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", log);
+    assert!(lines[1].ends_with("pc 4, line 5: `inc r0` as `inc r0`"), "{}", lines[1]);
+    let output = interpret("inline_annotate", ".inline bad\ninc r9\n.endinline\ninvoke bad\n", &["--annotate-errors"], "");
+    assert!(stderr(&output).ends_with("r9 does not exist\n4 | inc r9\n  |     ^^\n"), "{}", stderr(&output));
+}
+
+#[test]
+fn trace_logs_every_executed_instruction() {
+    let output = interpret("trace", "movi r0, 2\nloop: dec r0\njnz loop\n\n", &["--trace", "--quiet"], "");
+    assert!(output.status.success());
+    let log = stderr(&output);
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 5, "{}", log);
+    assert!(lines[0].ends_with("pc 0, line 1: `movi r0, 2` as `movi r0, 2`"), "{}", lines[0]);
+    assert!(lines[3].ends_with("pc 1, line 2: `loop: dec r0` as `dec r0`"), "{}", lines[3]);
+    assert!(lines[4].ends_with("pc 2, line 3: `jnz loop` as `jnz loop`"), "{}", lines[4]);
+}
+
+#[test]
+fn json_shows_the_final_state() {
+    let output = interpret("json_state", "movi r0, 7\nmovi r1, 1\ncmp r1, r0\n", &["--json"], "");
+    assert!(output.status.success());
+    let json = "{\"registers\": [7, 1, 0, 0, 0, 0, 0, 0], \"zero\": false, \"carry\": true, \"sign\": true, \"overflow\": false, \"pc\": 3}\n";
+    assert_eq!(stdout(&output), json);
+    assert!(stderr(&output).contains("Finished:"));
+    let output = interpret("json_stats", "inc r0\n", &["--json", "--stats"], "");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("cannot be used with --foreach or --stats"));
+}
+
+#[test]
+fn gzip_compressed_programs_are_decompressed() {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(b"movi r0, 6\nadd r1, r0, r0\n").unwrap();
+    let compressed = encoder.finish().unwrap();
+    // The second file has no `.gz` extension and is recognized by its contents.
+    for (name, contents) in [("gzip.s.gz", &compressed[..]), ("gzip_magic.s", &compressed[..]), ("gzip_broken.s.gz", &compressed[..12])] {
+        let path = std::env::temp_dir().join(format!("basic_asm_interpreter_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_basic_asm_interpreter")).arg(&path).args(["--json", "--quiet"]).output().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        if name == "gzip_broken.s.gz" {
+            assert!(!output.status.success() && stderr(&output).contains("unable to decompress"), "{}", stderr(&output));
+        } else {
+            assert!(stdout(&output).starts_with("{\"registers\": [6, 12, 0"), "{}", stderr(&output));
+        }
+    }
+}
+
+#[test]
+fn parse_only_lists_the_parsed_program_without_running_it() {
+    let source = "start: movi r0, 3\n// just a comment\nloop: dec r0\njnz loop\ndiv r0, r0, r1\n";
+    let output = interpret("parse_only", source, &["--parse-only"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    let listing = "0  Movi { to: 0, value: 3 }\n1  Noop\n2  Dec { reg: 0 }\n3  Jnz { label: \"2\" }\n4  Div { to: 0, op1: 0, op2: 1 }\n";
+    assert_eq!(stdout(&output), format!("{}Labels:\nstart: 0\nloop: 2\n", listing));
+}
+
+#[test]
+fn stats_count_the_executions_of_each_mnemonic() {
+    let output = interpret("stats_mnemonics", "movi r0, 3\nl: dec r0\n\njnz l\n", &["--stats", "--no-dump"], "");
+    let stdout = stdout(&output);
+    let executed = stdout.split_once("Executed:\n").map(|(_, counts)| counts).unwrap_or_default();
+    assert_eq!(executed, "  (no instruction): 3\n  dec: 3\n  jnz: 3\n  movi: 1\n", "{}", stdout);
+    assert!(stdout.contains("Steps: 10\n"));
+}
+
+#[test]
+fn warn_unreachable_flags_lines_no_path_reaches() {
+    let source = "j main\ninc r0\n// comment\nmain: call sub\ncmp r0, r0\nskipz\ninc r1\nj end\nsub: ret\ninc r2\nend: inc r3\n";
+    let output = interpret("unreachable", source, &["--warn-unreachable", "--quiet"], "");
+    let stderr = stderr(&output);
+    let warnings: Vec<&str> = stderr.lines().collect();
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+    assert!(warnings[0].ends_with("line 2 is unreachable") && warnings[1].ends_with("line 10 is unreachable"), "{:?}", warnings);
+}
+
+#[test]
+fn timeout_stops_a_program_running_too_long() {
+    let output = interpret("timeout", "loop: inc r0\nj loop\n", &["--timeout", "0.2", "--max-steps", "0"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("timeout of 200ms reached at line"), "{}", stderr(&output));
+    for value in ["0", "-1", "soon"] {
+        let output = interpret("timeout_invalid", "inc r0\n", &["--timeout", value], "");
+        assert!(!output.status.success(), "{}", value);
+    }
+}
+
+#[test]
+fn a_state_file_initializes_registers_memory_and_sp() {
+    let path = std::env::temp_dir().join(format!("basic_asm_interpreter_state_file_{}", std::process::id()));
+    std::fs::write(&path, "# an array of two\nmem[0x10] = 40\nmem[17] = 2\n\nr0 = 16\nR1 = 7\nsp = 100\n").unwrap();
+    let source = "load r2, r0\ninc r0\nload r3, r0\nadd r2, r2, r3\npush r1\nmovi r5, 99\nload r4, r5\n";
+    let output = interpret("state_file", source, &["--state", path.to_str().unwrap(), "r1=9", "--json", "--quiet"], "");
+    std::fs::write(&path, "mem[5000] = 1\n").unwrap();
+    let out_of_range = interpret("state_file_range", "inc r0\n", &["--state", path.to_str().unwrap()], "");
+    std::fs::write(&path, "r0: 1\n").unwrap();
+    let malformed = interpret("state_file_malformed", "inc r0\n", &["--state", path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert!(stdout(&output).starts_with("{\"registers\": [17, 9, 42, 2, 9, 99"), "{}{}", stdout(&output), stderr(&output));
+    assert!(stderr(&out_of_range).contains("address 5000 on line 1"), "{}", stderr(&out_of_range));
+    assert!(stderr(&malformed).contains("malformed line 1 in state file"), "{}", stderr(&malformed));
+}
+
+#[test]
+fn halt_sets_the_exit_code() {
+    let output = interpret("halt_code", "movi r0, 3\nhalt r0\nmovi r0, 4\n", &["--json", "--quiet"], "");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stdout(&output).starts_with("{\"registers\": [3, "), "{}", stdout(&output));
+    assert_eq!(interpret("halt_plain", "halt\n", &["--quiet"], "").status.code(), Some(0));
+}
+
+#[test]
+fn quitting_the_prompt_after_the_run_keeps_its_exit_code() {
+    let output = interpret("quit_after_halt", "movi r0, 6\nhalt r0", &["--interactive", "--quiet"], "set r1 2\nquit\n");
+    assert_eq!(output.status.code(), Some(6));
+    let path = std::env::temp_dir().join(format!("basic_asm_interpreter_quit_expected_{}.json", std::process::id()));
+    std::fs::write(&path, "{\"registers\": [3, 0, 0, 0, 0, 0, 0, 0], \"zero\": false}\n").unwrap();
+    let output = interpret("quit_after_a_mismatch", "movi r0, 4", &["--interactive", "--quiet", "--diff-state", path.to_str().unwrap()], "quit\n");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("R0: expected 3, got 4"));
+}
+
+#[test]
+fn emitted_programs_keep_their_directives() {
+    let source = ".stack 16\n.alias counter r2\nmovi counter, 3\nlea r3, table\nloop: loadd r4, r3, 0 // sum the table\nadd r0, r0, r4\ninc r3\ndec counter\njnz loop\npush r0\npop r1\nj end\ntable: .byte 5, 6, 7\nend:\n";
+    let emitted = interpret("emit_directives", source, &["--emit-resolved"], "");
+    assert!(emitted.status.success(), "{}", stderr(&emitted));
+    let emitted = stdout(&emitted);
+    assert_eq!(emitted.lines().count(), source.lines().count());
+    let lines: Vec<&str> = emitted.lines().collect();
+    assert_eq!([lines[0], lines[1], lines[8], lines[12]], [".stack 16", ".alias counter r2", "jnz     4", "table: .byte 5, 6, 7"]);
+    let original = interpret("emit_directives_original", source, &["--json", "--quiet"], "");
+    let round_trip = interpret("emit_directives_round_trip", &emitted, &["--json", "--quiet"], "");
+    assert_eq!(stdout(&original), stdout(&round_trip));
+    assert!(stdout(&original).starts_with("{\"registers\": [18, 18, 0, 3, 7, 0, 0, 0]"), "{}", stdout(&original));
+}
+
+#[test]
+fn coop_rejects_options_for_a_single_program() {
+    let other = std::env::temp_dir().join(format!("basic_asm_interpreter_coop_other_{}.s", std::process::id()));
+    std::fs::write(&other, "inc r0\n").unwrap();
+    let rejected = ["--trace", "--stuck-detect"].map(|option| interpret("coop_single", "inc r0\n", &["--coop", other.to_str().unwrap(), option], ""));
+    let output = interpret("coop_pair", "push r0\n", &["--coop", other.to_str().unwrap(), "--quiet"], "");
+    std::fs::remove_file(&other).unwrap();
+    for (output, option) in rejected.iter().zip(["--trace", "--stuck-detect"]) {
+        assert_eq!(output.status.code(), Some(1));
+        assert!(stderr(output).contains(&format!("--coop runs several programs, so it cannot be used with {}", option)), "{}", stderr(output));
+    }
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn label_output_names_the_source_in_the_json_result() {
+    let output = interpret("label_json", "inc r0\n", &["--json", "--quiet", "--label-output"], "");
+    assert!(output.status.success());
+    let name = format!("basic_asm_interpreter_label_json_{}.s\", \"registers\": [1, ", std::process::id());
+    assert!(stdout(&output).starts_with("{\"source\": \"") && stdout(&output).contains(&name), "{}", stdout(&output));
+    let output = interpret("plain_json", "inc r0\n", &["--json", "--quiet"], "");
+    assert!(stdout(&output).starts_with("{\"registers\": [1,"));
+}